@@ -1,6 +1,7 @@
 use std::error::Error;
 use std::fmt::{self, Display};
 use std::io;
+use std::path::PathBuf;
 
 /// Possible errors that occur when interacting with [KvStore](crate::KvStore).
 #[derive(Debug)]
@@ -11,6 +12,32 @@ pub enum KvsError {
     Io { cause: io::Error },
     /// Error (de)serializing the data in the store
     Serialization { cause: Box<bincode::ErrorKind> },
+    /// Error (de)serializing the JSON data in the store
+    Json { cause: serde_json::Error },
+    /// A log file was written in a format this version doesn't understand
+    UnsupportedFormat { file_num: u64, reason: String },
+    /// The index pointed `key` at a log entry that isn't a set, meaning the
+    /// index and log are out of sync
+    IndexInconsistent {
+        key: String,
+        file_num: u64,
+        pos: u64,
+    },
+    /// A key was rejected by the key validation enabled in
+    /// [KvStoreOptions](crate::KvStoreOptions)
+    InvalidKey { reason: String },
+    /// Tried to write to a store opened as a follower
+    ReadOnly,
+    /// The directory holding the log was deleted while the store was open.
+    /// See [KvStore::reopen](crate::KvStore::reopen)
+    DataDirMissing { path: PathBuf },
+    /// A write would make the log larger than
+    /// [KvStoreOptions::max_total_bytes](crate::KvStoreOptions::max_total_bytes)
+    StorageFull { max_total_bytes: u64 },
+    /// The log files don't fit together, e.g. because some are missing
+    Corruption { reason: String },
+    /// A byte range of the value of `key` would split a character
+    InvalidRange { key: String, offset: u64, len: u64 },
 }
 
 /// Alias for a `kvs` operation that may fail.
@@ -24,6 +51,12 @@ impl From<Box<bincode::ErrorKind>> for KvsError {
     }
 }
 
+impl From<serde_json::Error> for KvsError {
+    fn from(json_error: serde_json::Error) -> Self {
+        Self::Json { cause: json_error }
+    }
+}
+
 impl From<io::Error> for KvsError {
     fn from(io_error: io::Error) -> Self {
         Self::Io { cause: io_error }
@@ -36,6 +69,31 @@ impl Display for KvsError {
             Self::KeyNotFound { key } => write!(f, "Key not found: {}", key),
             Self::Io { cause } => write!(f, "Io: {}", cause),
             Self::Serialization { cause } => write!(f, "Serialization: {}", cause),
+            Self::Json { cause } => write!(f, "Json: {}", cause),
+            Self::UnsupportedFormat { file_num, reason } => {
+                write!(f, "Unsupported format in log file {}: {}", file_num, reason)
+            }
+            Self::IndexInconsistent { key, file_num, pos } => write!(
+                f,
+                "Index inconsistent: {} points to a removal in log file {} at {}",
+                key, file_num, pos
+            ),
+            Self::InvalidKey { reason } => write!(f, "Invalid key: {}", reason),
+            Self::ReadOnly => write!(f, "Store is read-only"),
+            Self::DataDirMissing { path } => {
+                write!(f, "Data directory {} is missing", path.display())
+            }
+            Self::StorageFull { max_total_bytes } => write!(
+                f,
+                "Storage full: the log would exceed {} bytes",
+                max_total_bytes
+            ),
+            Self::Corruption { reason } => write!(f, "Corrupt log: {}", reason),
+            Self::InvalidRange { key, offset, len } => write!(
+                f,
+                "Invalid range: {} bytes at {} of the value of {} split a character",
+                len, offset, key
+            ),
         }
     }
 }
@@ -45,6 +103,15 @@ impl Error for KvsError {
         match self {
             Self::Io { .. } => "IO error occurred",
             Self::Serialization { .. } => "Serialization error occurred",
+            Self::Json { .. } => "JSON serialization error occurred",
+            Self::UnsupportedFormat { .. } => "Log file format is unsupported",
+            Self::IndexInconsistent { .. } => "Index is inconsistent with the log",
+            Self::InvalidKey { .. } => "Key is invalid",
+            Self::ReadOnly => "Store is read-only",
+            Self::DataDirMissing { .. } => "Data directory is missing",
+            Self::StorageFull { .. } => "Storage is full",
+            Self::Corruption { .. } => "Log is corrupt",
+            Self::InvalidRange { .. } => "Range splits a character",
             _ => "Key not found",
         }
     }
@@ -53,6 +120,7 @@ impl Error for KvsError {
         match self {
             Self::Io { cause } => Some(cause),
             Self::Serialization { cause } => Some(cause),
+            Self::Json { cause } => Some(cause),
             _ => None,
         }
     }
@@ -0,0 +1,776 @@
+use crate::{KvsError, KvsResult};
+
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version of the on-disk format written by this version of the crate. Log
+/// files without a header predate versioning and are treated as version 1
+/// with [LogEncoding::Bincode].
+///
+/// * 1: initial format
+/// * 2: entries record when they were written
+/// * 3: the header records the byte order of bincode-encoded entries
+/// * 4: batches of entries that are applied together or not at all
+/// * 5: keys may be interned, see [Op::DefineKey]
+/// * 6: sets carry the flags of their value, see
+///   [KvStore::set_with_flags](crate::KvStore::set_with_flags)
+const FORMAT_VERSION: u8 = 6;
+
+/// Upper bound on the size of a header line, so a foreign file can't make us
+/// read it in its entirety looking for a newline
+const MAX_HEADER_LEN: usize = 256;
+
+/// How the entries of the write-ahead log are encoded.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogEncoding {
+    /// Compact binary encoding. The default.
+    #[default]
+    Bincode,
+    /// Newline-delimited JSON, which is larger and slower but can be inspected
+    /// with tools like `tail` and `jq`.
+    Json,
+}
+
+/// Byte order of the integers in bincode-encoded entries. Log files record
+/// theirs, so they can be read whatever byte order new log files are written
+/// with.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ByteOrder {
+    /// What every log file before format version 3 uses
+    #[default]
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    /// Byte order of new log files, chosen at build time with the
+    /// `big_endian` feature.
+    #[cfg(not(feature = "big_endian"))]
+    pub(crate) const WRITTEN: ByteOrder = ByteOrder::Little;
+    #[cfg(feature = "big_endian")]
+    pub(crate) const WRITTEN: ByteOrder = ByteOrder::Big;
+
+    /// The bincode configuration every entry in this byte order is
+    /// (de)serialized with.
+    fn bincode(self) -> bincode::Config {
+        let mut config = bincode::config();
+        match self {
+            ByteOrder::Little => config.little_endian(),
+            ByteOrder::Big => config.big_endian(),
+        };
+        config
+    }
+}
+
+/// How the entries of a single log file are laid out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct SegmentFormat {
+    pub(crate) version: u8,
+    pub(crate) encoding: LogEncoding,
+    pub(crate) byte_order: ByteOrder,
+}
+
+impl SegmentFormat {
+    /// Format of log files written by this version of the crate.
+    pub(crate) fn current(encoding: LogEncoding) -> SegmentFormat {
+        SegmentFormat {
+            version: FORMAT_VERSION,
+            encoding,
+            byte_order: ByteOrder::WRITTEN,
+        }
+    }
+}
+
+/// First line of every log file. Always JSON so it doesn't get in the way of
+/// inspecting JSON-encoded logs.
+#[derive(Debug, Deserialize, Serialize)]
+struct Header {
+    version: u8,
+    encoding: LogEncoding,
+    /// Missing before version 3
+    #[serde(default)]
+    byte_order: ByteOrder,
+}
+
+/// Entry in the write-ahead log. `unix_ms` is when the entry was written, in
+/// milliseconds since the Unix epoch, or 0 for entries from version 1 log
+/// files. `flags` are stored for clients alongside the value, or 0 for
+/// entries from before version 6.
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub(crate) enum Op {
+    Set {
+        key: String,
+        value: String,
+        unix_ms: u64,
+        flags: u32,
+    },
+    Rm {
+        key: String,
+        unix_ms: u64,
+    },
+    /// Marks the `count` entries following it in the same log file as a
+    /// batch. If any of them are missing, e.g. because of a crash part way
+    /// through writing them, none of them are applied.
+    Batch {
+        count: u64,
+        unix_ms: u64,
+    },
+    /// Assigns `key_id` to `key` for the entries after it in the same log
+    /// file, so sets of long keys don't have to repeat them. Ids are only
+    /// meaningful within the log file that defines them.
+    DefineKey {
+        key_id: u64,
+        key: String,
+    },
+    /// Set of the key defined as `key_id` earlier in the same log file
+    SetById {
+        key_id: u64,
+        value: String,
+        unix_ms: u64,
+        flags: u32,
+    },
+}
+
+/// Variant of [Op::SetById] in bincode-encoded entries
+const SET_BY_ID_VARIANT: u32 = 4;
+
+/// Layouts of older format versions, which are converted to the current [Op]
+/// when read.
+mod v1 {
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub(super) enum Op {
+        Set { key: String, value: String },
+        Rm { key: String },
+    }
+
+    impl From<Op> for super::Op {
+        fn from(op: Op) -> super::Op {
+            match op {
+                Op::Set { key, value } => super::Op::Set {
+                    key,
+                    value,
+                    unix_ms: 0,
+                    flags: 0,
+                },
+                Op::Rm { key } => super::Op::Rm { key, unix_ms: 0 },
+            }
+        }
+    }
+}
+
+/// Layout of versions 2 to 5, whose sets don't have flags.
+mod v5 {
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub(super) enum Op {
+        Set {
+            key: String,
+            value: String,
+            unix_ms: u64,
+        },
+        Rm {
+            key: String,
+            unix_ms: u64,
+        },
+        Batch {
+            count: u64,
+            unix_ms: u64,
+        },
+        DefineKey {
+            key_id: u64,
+            key: String,
+        },
+        SetById {
+            key_id: u64,
+            value: String,
+            unix_ms: u64,
+        },
+    }
+
+    impl From<Op> for super::Op {
+        fn from(op: Op) -> super::Op {
+            match op {
+                Op::Set {
+                    key,
+                    value,
+                    unix_ms,
+                } => super::Op::Set {
+                    key,
+                    value,
+                    unix_ms,
+                    flags: 0,
+                },
+                Op::Rm { key, unix_ms } => super::Op::Rm { key, unix_ms },
+                Op::Batch { count, unix_ms } => super::Op::Batch { count, unix_ms },
+                Op::DefineKey { key_id, key } => super::Op::DefineKey { key_id, key },
+                Op::SetById {
+                    key_id,
+                    value,
+                    unix_ms,
+                } => super::Op::SetById {
+                    key_id,
+                    value,
+                    unix_ms,
+                    flags: 0,
+                },
+            }
+        }
+    }
+}
+
+/// Current time in milliseconds since the Unix epoch, for timestamping
+/// entries.
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+}
+
+/// Write a header for `encoding` if `segment` is empty. A non-empty `segment`
+/// must already be in the current format.
+pub(crate) fn init_segment<W: Write + Seek>(
+    segment: &mut W,
+    encoding: LogEncoding,
+) -> KvsResult<SegmentFormat> {
+    if segment.seek(SeekFrom::End(0))? == 0 {
+        let mut line = serde_json::to_vec(&Header {
+            version: FORMAT_VERSION,
+            encoding,
+            byte_order: ByteOrder::WRITTEN,
+        })?;
+        line.push(b'\n');
+        segment.write_all(&line)?;
+        segment.flush()?;
+    }
+    Ok(SegmentFormat::current(encoding))
+}
+
+/// Read the header of a log file, returning the format of its entries and
+/// the position of the first one, or `None` if the file is empty.
+pub(crate) fn read_header<R: Read + Seek>(
+    segment: &mut R,
+    file_num: u64,
+) -> KvsResult<Option<(SegmentFormat, u64)>> {
+    segment.seek(SeekFrom::Start(0))?;
+    let mut line = Vec::new();
+    BufReader::new((&mut *segment).take(MAX_HEADER_LEN as u64)).read_until(b'\n', &mut line)?;
+    match line.first() {
+        None => Ok(None),
+        // Headers are JSON objects, which bincode-encoded entries can never start
+        // with
+        Some(b'{') => {
+            let header: Header =
+                serde_json::from_slice(&line).map_err(|_| KvsError::UnsupportedFormat {
+                    file_num,
+                    reason: "malformed header".to_owned(),
+                })?;
+            if header.version == 0 || header.version > FORMAT_VERSION {
+                return Err(KvsError::UnsupportedFormat {
+                    file_num,
+                    reason: format!("unknown format version {}", header.version),
+                });
+            }
+            let format = SegmentFormat {
+                version: header.version,
+                encoding: header.encoding,
+                byte_order: header.byte_order,
+            };
+            Ok(Some((format, line.len() as u64)))
+        }
+        Some(_) => Ok(Some((
+            SegmentFormat {
+                version: 1,
+                encoding: LogEncoding::Bincode,
+                byte_order: ByteOrder::Little,
+            },
+            0,
+        ))),
+    }
+}
+
+/// Write `op` to the end of `segment` in the current format, returning its
+/// position and length in bytes. The buffer is flushed before returning so
+/// errors are surfaced and the record is immediately visible to reads.
+pub(crate) fn write_op<W: Write + Seek>(
+    segment: &mut W,
+    encoding: LogEncoding,
+    op: &Op,
+) -> KvsResult<(u64, u64)> {
+    let pos = segment.seek(SeekFrom::End(0))?;
+    let mut buf = Vec::new();
+    encode_op(&mut buf, encoding, op)?;
+    segment.write_all(&buf)?;
+    segment.flush()?;
+    Ok((pos, buf.len() as u64))
+}
+
+/// Like [write_op] for many entries at once, going through a single buffer
+/// that is flushed at the end.
+pub(crate) fn write_ops<W: Write + Seek>(
+    segment: &mut W,
+    encoding: LogEncoding,
+    ops: &[Op],
+) -> KvsResult<Vec<(u64, u64)>> {
+    let mut pos = segment.seek(SeekFrom::End(0))?;
+    let mut writer = BufWriter::new(segment);
+    let mut buf = Vec::new();
+    let mut entries = Vec::with_capacity(ops.len());
+    for op in ops {
+        buf.clear();
+        encode_op(&mut buf, encoding, op)?;
+        writer.write_all(&buf)?;
+        entries.push((pos, buf.len() as u64));
+        pos += buf.len() as u64;
+    }
+    writer.flush()?;
+    Ok(entries)
+}
+
+/// Size of `op` once encoded, without encoding it.
+pub(crate) fn encoded_len(encoding: LogEncoding, op: &Op) -> KvsResult<u64> {
+    Ok(match encoding {
+        LogEncoding::Bincode => ByteOrder::WRITTEN.bincode().serialized_size(op)?,
+        LogEncoding::Json => serde_json::to_vec(op)?.len() as u64 + 1,
+    })
+}
+
+/// Append the encoded `op` to `buf`.
+fn encode_op(buf: &mut Vec<u8>, encoding: LogEncoding, op: &Op) -> KvsResult<()> {
+    match encoding {
+        LogEncoding::Bincode => ByteOrder::WRITTEN.bincode().serialize_into(&mut *buf, op)?,
+        LogEncoding::Json => {
+            serde_json::to_writer(&mut *buf, op)?;
+            buf.push(b'\n');
+        }
+    }
+    Ok(())
+}
+
+/// Read the entry at `pos`.
+pub(crate) fn read_op<R: Read + Seek>(
+    reader: &mut R,
+    format: SegmentFormat,
+    pos: u64,
+) -> KvsResult<Op> {
+    reader.seek(SeekFrom::Start(pos))?;
+    match format.encoding {
+        LogEncoding::Bincode => decode_bincode(reader, format),
+        LogEncoding::Json => {
+            let mut line = String::new();
+            BufReader::new(reader).read_line(&mut line)?;
+            decode_json(&line, format.version)
+        }
+    }
+}
+
+/// Read at most `len` bytes of the value of the entry at `pos`, starting
+/// `offset` bytes into it. Bincode entries are read in place, skipping the
+/// rest of the value, while JSON ones have to be decoded in full. Returns
+/// `None` if the entry isn't a set.
+pub(crate) fn read_value_range<R: Read + Seek>(
+    reader: &mut R,
+    format: SegmentFormat,
+    pos: u64,
+    offset: u64,
+    len: u64,
+) -> KvsResult<Option<Vec<u8>>> {
+    if format.encoding == LogEncoding::Json {
+        return Ok(match read_op(reader, format, pos)? {
+            Op::Set { value, .. } | Op::SetById { value, .. } => {
+                let start = offset.min(value.len() as u64) as usize;
+                let end = offset.saturating_add(len).min(value.len() as u64) as usize;
+                Some(value.as_bytes()[start..end].to_vec())
+            }
+            _ => None,
+        });
+    }
+    // Sets are variant 0 in every version, followed by the length-prefixed
+    // key and value. Sets by id have the id in place of the key
+    let config = format.byte_order.bincode();
+    reader.seek(SeekFrom::Start(pos))?;
+    match config.deserialize_from::<_, u32>(&mut *reader)? {
+        0 => {
+            let key_len: u64 = config.deserialize_from(&mut *reader)?;
+            reader.seek(SeekFrom::Current(key_len as i64))?;
+        }
+        SET_BY_ID_VARIANT => {
+            config.deserialize_from::<_, u64>(&mut *reader)?;
+        }
+        _ => return Ok(None),
+    }
+    let value_len: u64 = config.deserialize_from(&mut *reader)?;
+    let offset = offset.min(value_len);
+    let len = len.min(value_len - offset);
+    reader.seek(SeekFrom::Current(offset as i64))?;
+    let mut buf = vec![0; len as usize];
+    reader.read_exact(&mut buf)?;
+    Ok(Some(buf))
+}
+
+fn decode_bincode<R: Read>(reader: R, format: SegmentFormat) -> KvsResult<Op> {
+    let config = format.byte_order.bincode();
+    Ok(match format.version {
+        1 => config.deserialize_from::<_, v1::Op>(reader)?.into(),
+        2..=5 => config.deserialize_from::<_, v5::Op>(reader)?.into(),
+        _ => config.deserialize_from(reader)?,
+    })
+}
+
+fn decode_json(line: &str, version: u8) -> KvsResult<Op> {
+    Ok(match version {
+        1 => serde_json::from_str::<v1::Op>(line)?.into(),
+        2..=5 => serde_json::from_str::<v5::Op>(line)?.into(),
+        _ => serde_json::from_str(line)?,
+    })
+}
+
+/// Iterates over the entries of a log file along with their positions. Stops
+/// at the end of the file or at the first entry that can't be decoded, e.g.
+/// one that was only partially written.
+pub(crate) struct OpIter<R: Read> {
+    reader: CountingReader<BufReader<R>>,
+    format: SegmentFormat,
+}
+
+impl<R: Read + Seek> OpIter<R> {
+    /// Iterate over the entries of `segment` starting at `pos`.
+    pub(crate) fn new(mut segment: R, format: SegmentFormat, pos: u64) -> KvsResult<OpIter<R>> {
+        segment.seek(SeekFrom::Start(pos))?;
+        Ok(OpIter {
+            reader: CountingReader {
+                inner: BufReader::new(segment),
+                pos,
+            },
+            format,
+        })
+    }
+
+    /// Position just past the last entry returned.
+    pub(crate) fn pos(&self) -> u64 {
+        self.reader.pos
+    }
+}
+
+impl<R: Read> Iterator for OpIter<R> {
+    type Item = (u64, Op);
+
+    fn next(&mut self) -> Option<(u64, Op)> {
+        let pos = self.reader.pos;
+        let op = match self.format.encoding {
+            LogEncoding::Bincode => decode_bincode(&mut self.reader, self.format).ok()?,
+            LogEncoding::Json => {
+                let mut line = String::new();
+                self.reader.read_line(&mut line).ok()?;
+                // A missing newline means the entry was cut off
+                if !line.ends_with('\n') {
+                    return None;
+                }
+                decode_json(&line, self.format.version).ok()?
+            }
+        };
+        Some((pos, op))
+    }
+}
+
+/// Keeps track of the position in the underlying reader.
+struct CountingReader<R> {
+    inner: R,
+    pos: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: BufRead> BufRead for CountingReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt);
+        self.pos += amt as u64;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    fn ops() -> Vec<Op> {
+        vec![
+            Op::Set {
+                key: "key1".to_owned(),
+                value: "line1\nline2".to_owned(),
+                unix_ms: 1,
+                flags: 5,
+            },
+            Op::Rm {
+                key: "key1".to_owned(),
+                unix_ms: 2,
+            },
+        ]
+    }
+
+    fn round_trip(encoding: LogEncoding) -> KvsResult<()> {
+        let mut segment = Cursor::new(Vec::new());
+        let format = init_segment(&mut segment, encoding)?;
+        let mut positions = Vec::new();
+        for op in ops() {
+            positions.push(write_op(&mut segment, encoding, &op)?.0);
+        }
+
+        let (header_format, start) = read_header(&mut segment, 1)?.unwrap();
+        assert_eq!(header_format, format);
+        let read: Vec<(u64, Op)> = OpIter::new(&mut segment, format, start)?.collect();
+        assert_eq!(
+            read.iter().map(|(pos, _)| *pos).collect::<Vec<u64>>(),
+            positions
+        );
+        assert_eq!(format!("{:?}", read[0].1), format!("{:?}", ops()[0]));
+        match read_op(&mut segment, format, positions[1])? {
+            Op::Rm { key, unix_ms } => {
+                assert_eq!(key, "key1");
+                assert_eq!(unix_ms, 2);
+            }
+            op => panic!("Unexpected op {:?}", op),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bincode_round_trip() -> KvsResult<()> {
+        round_trip(LogEncoding::Bincode)
+    }
+
+    #[test]
+    fn json_round_trip() -> KvsResult<()> {
+        round_trip(LogEncoding::Json)
+    }
+
+    #[test]
+    fn encoded_lengths() -> KvsResult<()> {
+        for &encoding in &[LogEncoding::Bincode, LogEncoding::Json] {
+            let batch = Op::Batch {
+                count: 2,
+                unix_ms: 3,
+            };
+            for op in ops().into_iter().chain(Some(batch)) {
+                let mut buf = Vec::new();
+                encode_op(&mut buf, encoding, &op)?;
+                assert_eq!(encoded_len(encoding, &op)?, buf.len() as u64);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn json_is_line_delimited() -> KvsResult<()> {
+        let mut segment = Cursor::new(Vec::new());
+        init_segment(&mut segment, LogEncoding::Json)?;
+        for op in ops() {
+            write_op(&mut segment, LogEncoding::Json, &op)?;
+        }
+        let text = String::from_utf8(segment.into_inner()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        for line in lines {
+            serde_json::from_str::<serde_json::Value>(line)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn headerless_segment_is_version_1() -> KvsResult<()> {
+        #[derive(Serialize)]
+        enum OpV1 {
+            Set { key: String, value: String },
+            Rm { key: String },
+        }
+
+        let mut bytes = Vec::new();
+        bincode::serialize_into(
+            &mut bytes,
+            &OpV1::Set {
+                key: "key1".to_owned(),
+                value: "value1".to_owned(),
+            },
+        )?;
+        bincode::serialize_into(
+            &mut bytes,
+            &OpV1::Rm {
+                key: "key1".to_owned(),
+            },
+        )?;
+        let mut segment = Cursor::new(bytes);
+        let (format, start) = read_header(&mut segment, 1)?.unwrap();
+        assert_eq!(
+            format,
+            SegmentFormat {
+                version: 1,
+                encoding: LogEncoding::Bincode,
+                byte_order: ByteOrder::Little,
+            }
+        );
+        assert_eq!(start, 0);
+
+        let read: Vec<(u64, Op)> = OpIter::new(&mut segment, format, start)?.collect();
+        assert_eq!(read.len(), 2);
+        match &read[0].1 {
+            Op::Set {
+                key,
+                value,
+                unix_ms,
+                flags,
+            } => assert_eq!(
+                (key.as_str(), value.as_str(), *unix_ms, *flags),
+                ("key1", "value1", 0, 0)
+            ),
+            op => panic!("Unexpected op {:?}", op),
+        }
+        match read_op(&mut segment, format, read[1].0)? {
+            Op::Rm { key, unix_ms } => assert_eq!((key.as_str(), unix_ms), ("key1", 0)),
+            op => panic!("Unexpected op {:?}", op),
+        }
+
+        assert_eq!(read_header(&mut Cursor::new(Vec::new()), 1)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn header_records_byte_order() -> KvsResult<()> {
+        let op = Op::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+            unix_ms: 1,
+            flags: 0,
+        };
+        for &byte_order in &[ByteOrder::Little, ByteOrder::Big] {
+            let mut bytes = format!(
+                "{{\"version\":{},\"encoding\":\"bincode\",\"byte_order\":\"{}\"}}\n",
+                FORMAT_VERSION,
+                if byte_order == ByteOrder::Big {
+                    "big"
+                } else {
+                    "little"
+                }
+            )
+            .into_bytes();
+            byte_order.bincode().serialize_into(&mut bytes, &op)?;
+            let mut segment = Cursor::new(bytes);
+            let (format, start) = read_header(&mut segment, 1)?.unwrap();
+            assert_eq!(format.byte_order, byte_order);
+            match read_op(&mut segment, format, start)? {
+                Op::Set { unix_ms, .. } => assert_eq!(unix_ms, 1),
+                op => panic!("Unexpected op {:?}", op),
+            }
+        }
+
+        // Version 2 headers don't have a byte order
+        let mut segment = Cursor::new(b"{\"version\":2,\"encoding\":\"bincode\"}\n".to_vec());
+        let (format, _) = read_header(&mut segment, 1)?.unwrap();
+        assert_eq!(format.byte_order, ByteOrder::Little);
+        Ok(())
+    }
+
+    #[test]
+    fn newer_format_version() {
+        let mut segment = Cursor::new(b"{\"version\":200,\"encoding\":\"json\"}\n".to_vec());
+        match read_header(&mut segment, 7) {
+            Err(KvsError::UnsupportedFormat { file_num, .. }) => assert_eq!(file_num, 7),
+            res => panic!("Unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn cut_off_entry() -> KvsResult<()> {
+        for &encoding in &[LogEncoding::Bincode, LogEncoding::Json] {
+            let mut segment = Cursor::new(Vec::new());
+            for op in ops() {
+                write_op(&mut segment, encoding, &op)?;
+            }
+            let mut bytes = segment.into_inner();
+            bytes.pop();
+            let format = SegmentFormat::current(encoding);
+            let read: Vec<(u64, Op)> = OpIter::new(Cursor::new(bytes), format, 0)?.collect();
+            assert_eq!(read.len(), 1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn value_range() -> KvsResult<()> {
+        for &encoding in &[LogEncoding::Bincode, LogEncoding::Json] {
+            let mut segment = Cursor::new(Vec::new());
+            let format = init_segment(&mut segment, encoding)?;
+            let ops = ops();
+            let (set_pos, _) = write_op(&mut segment, encoding, &ops[0])?;
+            let (rm_pos, _) = write_op(&mut segment, encoding, &ops[1])?;
+            let range = |segment: &mut Cursor<Vec<u8>>, offset, len| {
+                read_value_range(segment, format, set_pos, offset, len)
+            };
+            assert_eq!(Some(b"line2".to_vec()), range(&mut segment, 6, 5)?);
+            assert_eq!(Some(b"2".to_vec()), range(&mut segment, 10, 100)?);
+            assert_eq!(Some(Vec::new()), range(&mut segment, 100, 5)?);
+            assert_eq!(None, read_value_range(&mut segment, format, rm_pos, 0, 5)?);
+            let (by_id_pos, _) = write_op(
+                &mut segment,
+                encoding,
+                &Op::SetById {
+                    key_id: 7,
+                    value: "value".to_owned(),
+                    unix_ms: 3,
+                    flags: 0,
+                },
+            )?;
+            assert_eq!(
+                Some(b"lu".to_vec()),
+                read_value_range(&mut segment, format, by_id_pos, 2, 2)?
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn version_5_sets_have_no_flags() -> KvsResult<()> {
+        #[derive(Serialize)]
+        enum OpV5 {
+            Set {
+                key: String,
+                value: String,
+                unix_ms: u64,
+            },
+        }
+
+        let op = OpV5::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+            unix_ms: 3,
+        };
+        let mut bincode_bytes = b"{\"version\":5,\"encoding\":\"bincode\"}\n".to_vec();
+        bincode::serialize_into(&mut bincode_bytes, &op)?;
+        let mut json_bytes = b"{\"version\":5,\"encoding\":\"json\"}\n".to_vec();
+        serde_json::to_writer(&mut json_bytes, &op)?;
+        json_bytes.push(b'\n');
+        for bytes in [bincode_bytes, json_bytes] {
+            let mut segment = Cursor::new(bytes);
+            let (format, start) = read_header(&mut segment, 1)?.unwrap();
+            match read_op(&mut segment, format, start)? {
+                Op::Set { unix_ms, flags, .. } => assert_eq!((unix_ms, flags), (3, 0)),
+                op => panic!("Unexpected op {:?}", op),
+            }
+        }
+        Ok(())
+    }
+}
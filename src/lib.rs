@@ -1,5 +1,15 @@
 mod error;
+mod format;
+mod namespace;
+mod storage;
 mod store;
 
 pub use error::*;
-pub use store::KvStore;
+pub use format::LogEncoding;
+pub use namespace::Namespace;
+pub use storage::{FsStorage, LogStorage};
+pub use store::{
+    Checkpoint, CompactionReport, DiskUsage, GetErrorPolicy, Health, IndexMismatch, KvStore,
+    KvStoreOptions, OpKind, OpSummary, ScanPage, Snapshot, SortedScan, Stats, VerifyReport,
+    WriteReport,
+};
@@ -1,9 +1,28 @@
-use kvs::{KvStore, KvsError};
+use kvs::{KvStore, KvsError, OpKind};
 
 use clap::{App, AppSettings, Arg, SubCommand};
 use std::error::Error;
+use std::io::{self, BufWriter, Write};
+use std::time::UNIX_EPOCH;
+
+/// Number of entries `kvs scan` reads at a time
+const SCAN_PAGE_LEN: usize = 1000;
 
 fn main() -> Result<(), Box<dyn Error>> {
+    match run() {
+        // Whatever is reading the output stopped, like `head` does
+        Err(err)
+            if err
+                .downcast_ref::<io::Error>()
+                .is_some_and(|err| err.kind() == io::ErrorKind::BrokenPipe) =>
+        {
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
     let args = App::new("kvs")
         .author("Carter Green")
         .about("Key value store")
@@ -30,6 +49,22 @@ fn main() -> Result<(), Box<dyn Error>> {
                         .index(2),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("setnx")
+                .help("Set the value of a key unless it's already set, exiting non-zero if so")
+                .arg(
+                    Arg::with_name("KEY")
+                        .help("Key where to store the value")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("VALUE")
+                        .help("Value to store under key")
+                        .required(true)
+                        .index(2),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("get")
                 .help("Get the value of a key")
@@ -38,6 +73,79 @@ fn main() -> Result<(), Box<dyn Error>> {
                         .help("Key whose value will be retrieved")
                         .required(true)
                         .index(1),
+                )
+                .arg(
+                    Arg::with_name("fail-on-missing")
+                        .long("fail-on-missing")
+                        .help("Exit with 1 and print nothing to stdout if the key is missing"),
+                )
+                .arg(
+                    Arg::with_name("null-exit-code")
+                        .long("null-exit-code")
+                        .takes_value(true)
+                        .value_name("N")
+                        .validator(|n| {
+                            n.parse::<i32>()
+                                .map(|_| ())
+                                .map_err(|_| "must be an integer".to_owned())
+                        })
+                        .help("Like --fail-on-missing, exiting with N instead"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("get-or")
+                .help("Get the value of a key, or a default if it isn't set")
+                .arg(
+                    Arg::with_name("KEY")
+                        .help("Key whose value will be retrieved")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("DEFAULT")
+                        .help("Value to print if the key isn't set")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("get-or-set")
+                .help("Get the value of a key, setting it to a default first if it isn't set")
+                .arg(
+                    Arg::with_name("KEY")
+                        .help("Key whose value will be retrieved")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("DEFAULT")
+                        .help("Value to store under key if it isn't set")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("get-range")
+                .help("Get part of the value of a key")
+                .arg(
+                    Arg::with_name("KEY")
+                        .help("Key whose value will be retrieved")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("OFFSET")
+                        .help("Byte offset into the value where to start")
+                        .required(true)
+                        .validator(is_u64)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("LEN")
+                        .help("Maximum number of bytes to retrieve")
+                        .required(true)
+                        .validator(is_u64)
+                        .index(3),
                 ),
         )
         .subcommand(
@@ -50,6 +158,175 @@ fn main() -> Result<(), Box<dyn Error>> {
                         .index(1),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("import")
+                .help("Set the key-value pairs in a file, one tab-separated pair per line")
+                .arg(
+                    Arg::with_name("FILE")
+                        .help("File of KEY<TAB>VALUE lines using \\t, \\n, \\r and \\\\ escapes")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("strict")
+                        .long("strict")
+                        .help("Import nothing if any line is invalid"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("multi-set")
+                .help("Set several keys together, so either all or none of them are set")
+                .arg(
+                    Arg::with_name("PAIR")
+                        .help("Keys each followed by their value")
+                        .multiple(true)
+                        .required_unless("file")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("file")
+                        .long("file")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Also set the pairs in a file of the format read by import"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rmprefix")
+                .help("Remove every key starting with a prefix")
+                .arg(
+                    Arg::with_name("PREFIX")
+                        .help("Prefix of the keys to remove")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("yes")
+                        .long("yes")
+                        .help("Confirm removing the keys"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rename")
+                .help("Move the value of a key to another key, overwriting it")
+                .arg(
+                    Arg::with_name("FROM")
+                        .help("Key whose value will be moved")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("TO")
+                        .help("Key where to store the value")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("keys")
+                .help("List keys in sorted order, one per line")
+                .arg(
+                    Arg::with_name("PREFIX")
+                        .help("Only list keys starting with this prefix")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .takes_value(true)
+                        .value_name("N")
+                        .validator(is_usize)
+                        .help("List at most N keys"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .help("Print entries in key order as lines that can be imported")
+                .arg(
+                    Arg::with_name("PREFIX")
+                        .help("Only print entries whose key starts with this prefix")
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("locate")
+                .help("Print the log file number and byte offset of a key's entry")
+                .arg(
+                    Arg::with_name("KEY")
+                        .help("Key to locate")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("last-modified")
+                .help("Print when a key was last set in milliseconds since the Unix epoch")
+                .arg(
+                    Arg::with_name("KEY")
+                        .help("Key whose modification time will be retrieved")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("prewarm")
+                .help("Read the entries of keys so they're cached by the operating system")
+                .arg(
+                    Arg::with_name("KEY")
+                        .help("Keys to read, every key if none are given")
+                        .multiple(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("recent-ops")
+                .help("Print the last entries in the log with their log file number and offset")
+                .arg(
+                    Arg::with_name("N")
+                        .help("Number of entries to print")
+                        .default_value("10")
+                        .validator(is_usize)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("disk-usage")
+                .help("Print the size of the log and how much of it holds current values"),
+        )
+        .subcommand(
+            SubCommand::with_name("health")
+                .help("Check that the store can be written to, exiting non-zero otherwise"),
+        )
+        .subcommand(
+            SubCommand::with_name("fsck")
+                .help("Check the index against the log, exiting non-zero on mismatches"),
+        )
+        .subcommand(
+            SubCommand::with_name("checkpoint")
+                .help("Make the log durable and print the log file number and offset it ends at")
+                .arg(
+                    Arg::with_name("compact")
+                        .long("compact")
+                        .help("Compact the log first"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("compact")
+                .help("Rewrite the log, reclaiming space used by stale entries")
+                .arg(
+                    Arg::with_name("min-ratio")
+                        .long("min-ratio")
+                        .takes_value(true)
+                        .value_name("RATIO")
+                        .validator(|ratio| {
+                            ratio
+                                .parse::<f64>()
+                                .map(|_| ())
+                                .map_err(|_| "must be a number".to_owned())
+                        })
+                        .help("Only compact if more than this fraction of the log is stale"),
+                ),
+        )
         .get_matches();
     if args.is_present("version") {
         println!("kvs version {}", env!("CARGO_PKG_VERSION"));
@@ -64,8 +341,53 @@ fn main() -> Result<(), Box<dyn Error>> {
                     sub.value_of("VALUE").unwrap().to_owned(),
                 )?;
             }
+            ("setnx", Some(sub)) => {
+                let created = store.set_nx(
+                    sub.value_of("KEY").unwrap().to_owned(),
+                    sub.value_of("VALUE").unwrap().to_owned(),
+                )?;
+                if !created {
+                    std::process::exit(1);
+                }
+            }
             ("get", Some(sub)) => {
                 let value = store.get(sub.value_of("KEY").unwrap().to_owned())?;
+                // Safe to unwrap because of the validator
+                let null_exit_code = match sub.value_of("null-exit-code") {
+                    Some(code) => Some(code.parse().unwrap()),
+                    None if sub.is_present("fail-on-missing") => Some(1),
+                    None => None,
+                };
+                match (value, null_exit_code) {
+                    (Some(value), _) => println!("{}", value),
+                    (None, Some(code)) => {
+                        eprintln!("Key not found");
+                        std::process::exit(code);
+                    }
+                    (None, None) => println!("Key not found"),
+                };
+            }
+            ("get-or", Some(sub)) => {
+                let value = store.get_or(
+                    sub.value_of("KEY").unwrap().to_owned(),
+                    sub.value_of("DEFAULT").unwrap().to_owned(),
+                )?;
+                println!("{}", value);
+            }
+            ("get-or-set", Some(sub)) => {
+                let value = store.get_or_set(
+                    sub.value_of("KEY").unwrap().to_owned(),
+                    sub.value_of("DEFAULT").unwrap().to_owned(),
+                )?;
+                println!("{}", value);
+            }
+            ("get-range", Some(sub)) => {
+                // Safe to unwrap because of the validators
+                let value = store.get_range(
+                    sub.value_of("KEY").unwrap().to_owned(),
+                    sub.value_of("OFFSET").unwrap().parse().unwrap(),
+                    sub.value_of("LEN").unwrap().parse().unwrap(),
+                )?;
                 match value {
                     Some(value) => println!("{}", value),
                     None => println!("Key not found"),
@@ -79,8 +401,252 @@ fn main() -> Result<(), Box<dyn Error>> {
                 }
                 res?;
             }
+            ("import", Some(sub)) => {
+                let (entries, skipped) = read_entries(sub.value_of("FILE").unwrap())?;
+                if skipped > 0 && sub.is_present("strict") {
+                    eprintln!("Nothing imported");
+                    std::process::exit(1);
+                }
+                let imported = entries.len();
+                store.bulk_set(entries)?;
+                println!("Imported {} keys, skipped {} lines", imported, skipped);
+            }
+            ("multi-set", Some(sub)) => {
+                let args: Vec<&str> = sub.values_of("PAIR").into_iter().flatten().collect();
+                if !args.len().is_multiple_of(2) {
+                    eprintln!("Missing the value of key {}", args[args.len() - 1]);
+                    std::process::exit(1);
+                }
+                let mut entries: Vec<(String, String)> = args
+                    .chunks(2)
+                    .map(|pair| (pair[0].to_owned(), pair[1].to_owned()))
+                    .collect();
+                if let Some(path) = sub.value_of("file") {
+                    let (file_entries, skipped) = read_entries(path)?;
+                    entries.extend(file_entries);
+                    if skipped > 0 {
+                        eprintln!("Nothing set");
+                        std::process::exit(1);
+                    }
+                }
+                store.multi_set(entries)?;
+            }
+            ("rmprefix", Some(sub)) => {
+                let prefix = sub.value_of("PREFIX").unwrap();
+                if sub.is_present("yes") {
+                    println!("{}", store.remove_prefix(prefix)?);
+                } else {
+                    eprintln!(
+                        "Would remove {} keys, pass --yes to remove them",
+                        store.keys(Some(prefix)).len()
+                    );
+                    std::process::exit(1);
+                }
+            }
+            ("rename", Some(sub)) => {
+                let res = store.rename(
+                    sub.value_of("FROM").unwrap().to_owned(),
+                    sub.value_of("TO").unwrap().to_owned(),
+                );
+                if let Err(KvsError::KeyNotFound { .. }) = res {
+                    println!("Key not found");
+                    std::process::exit(1);
+                }
+                res?;
+            }
+            ("keys", Some(sub)) => {
+                // Safe to unwrap because of the validator
+                let limit = sub
+                    .value_of("limit")
+                    .map_or(usize::MAX, |n| n.parse().unwrap());
+                let mut out = BufWriter::new(io::stdout().lock());
+                for key in store.keys(sub.value_of("PREFIX")).iter().take(limit) {
+                    writeln!(out, "{}", escape(key))?;
+                }
+                out.flush()?;
+            }
+            ("scan", Some(sub)) => {
+                let prefix = sub.value_of("PREFIX").unwrap_or("");
+                let mut out = BufWriter::new(io::stdout().lock());
+                let mut cursor = None;
+                loop {
+                    let page = store.scan_page(prefix, cursor.as_deref(), SCAN_PAGE_LEN)?;
+                    for (key, value) in &page.entries {
+                        writeln!(out, "{}\t{}", escape(key), escape(value))?;
+                    }
+                    cursor = match page.next_cursor {
+                        Some(next_cursor) => Some(next_cursor),
+                        None => break,
+                    };
+                }
+                out.flush()?;
+            }
+            ("locate", Some(sub)) => match store.locate(sub.value_of("KEY").unwrap()) {
+                Some((file_num, pos)) => println!("{} {}", file_num, pos),
+                None => println!("Key not found"),
+            },
+            ("last-modified", Some(sub)) => {
+                match store.last_modified(sub.value_of("KEY").unwrap()) {
+                    Some(time) => println!("{}", time.duration_since(UNIX_EPOCH)?.as_millis()),
+                    None => println!("Key not found"),
+                }
+            }
+            ("prewarm", Some(sub)) => match sub.values_of("KEY") {
+                Some(keys) => store.prewarm(&keys.map(str::to_owned).collect::<Vec<_>>())?,
+                None => store.prewarm_all()?,
+            },
+            ("recent-ops", Some(sub)) => {
+                // Safe to unwrap because of the default and validator
+                let n = sub.value_of("N").unwrap().parse().unwrap();
+                let mut out = BufWriter::new(io::stdout().lock());
+                for (file_num, pos, summary) in store.recent_ops(n)? {
+                    let kind = match summary.kind {
+                        OpKind::Set => "set",
+                        OpKind::Remove => "rm",
+                    };
+                    writeln!(
+                        out,
+                        "{} {} {} {}",
+                        file_num,
+                        pos,
+                        kind,
+                        escape(&summary.key)
+                    )?;
+                }
+                out.flush()?;
+            }
+            ("disk-usage", Some(_)) => {
+                let usage = store.disk_usage()?;
+                println!("log bytes: {}", usage.total_log_bytes);
+                println!("live bytes (estimate): {}", usage.live_value_bytes_estimate);
+                println!("log files: {}", usage.num_segments);
+            }
+            ("health", Some(_)) => {
+                let health = store.health();
+                println!("{}", health.detail);
+                if !health.ok {
+                    std::process::exit(1);
+                }
+            }
+            ("fsck", Some(_)) => {
+                let report = store.verify()?;
+                for mismatch in &report.mismatches {
+                    println!(
+                        "{}: log file {} at {}: {}",
+                        mismatch.key, mismatch.file_num, mismatch.pos, mismatch.reason
+                    );
+                }
+                println!(
+                    "{} keys checked, {} mismatches",
+                    report.keys_checked,
+                    report.mismatches.len()
+                );
+                if !report.is_ok() {
+                    std::process::exit(1);
+                }
+            }
+            ("checkpoint", Some(sub)) => {
+                if sub.is_present("compact") {
+                    store.compact()?;
+                }
+                let checkpoint = store.checkpoint()?;
+                println!("{} {}", checkpoint.file_num, checkpoint.offset);
+            }
+            ("compact", Some(sub)) => match sub.value_of("min-ratio") {
+                // Safe to unwrap because of the validator
+                Some(ratio) => {
+                    if !store.compact_if(ratio.parse().unwrap())? {
+                        println!("Not enough stale entries, not compacting");
+                    }
+                }
+                None => store.compact()?,
+            },
             _ => panic!("Unexpected subcommand"),
         }
     }
     Ok(())
 }
+
+/// Read the entries of an import file, printing why each line that can't be
+/// parsed is skipped. Returns the entries and the number of lines skipped.
+fn read_entries(path: &str) -> io::Result<(Vec<(String, String)>, usize)> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut entries = Vec::new();
+    let mut skipped = 0;
+    for (line_num, line) in contents.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        match parse_import_line(line) {
+            Ok(entry) => entries.push(entry),
+            Err(reason) => {
+                eprintln!("line {}: {}", line_num + 1, reason);
+                skipped += 1;
+            }
+        }
+    }
+    Ok((entries, skipped))
+}
+
+/// Split a line of an import file into its unescaped key and value.
+fn parse_import_line(line: &str) -> Result<(String, String), String> {
+    let mut fields = line.split('\t');
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some(key), Some(value), None) => Ok((unescape(key)?, unescape(value)?)),
+        (_, None, _) => Err("missing tab between key and value".to_owned()),
+        _ => Err("more than one tab, escape tabs as \\t".to_owned()),
+    }
+}
+
+/// Escape tabs, newlines, carriage returns and backslashes in `field` so it
+/// can be imported.
+fn escape(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\\' => escaped.push_str("\\\\"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Replace the escape sequences `\t`, `\n`, `\r` and `\\` in `field`.
+fn unescape(field: &str) -> Result<String, String> {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('\\') => unescaped.push('\\'),
+            Some(other) => return Err(format!("unknown escape sequence \\{}", other)),
+            None => return Err("trailing backslash".to_owned()),
+        }
+    }
+    Ok(unescaped)
+}
+
+/// Validator for arguments that must be counts of keys or entries.
+fn is_usize(value: String) -> Result<(), String> {
+    value
+        .parse::<usize>()
+        .map(|_| ())
+        .map_err(|_| "must be a non-negative integer".to_owned())
+}
+
+/// Validator for arguments that must be byte counts.
+fn is_u64(value: String) -> Result<(), String> {
+    value
+        .parse::<u64>()
+        .map(|_| ())
+        .map_err(|_| "must be a non-negative integer".to_owned())
+}
@@ -0,0 +1,104 @@
+/// Builds keys out of components joined by a separator, e.g. `user:123:name`,
+/// so related keys share a prefix that can be listed with
+/// [KvStore::keys](crate::KvStore::keys) or
+/// [KvStore::scan_page](crate::KvStore::scan_page). Separators and
+/// backslashes inside components are escaped with a backslash, so a
+/// component can never be mistaken for several.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Namespace {
+    separator: char,
+    /// Escaped components of the namespace, each followed by the separator
+    prefix: String,
+}
+
+const ESCAPE: char = '\\';
+
+impl Namespace {
+    /// The top-level namespace, whose keys have no prefix. Panics if
+    /// `separator` is a backslash, which is used for escaping.
+    pub fn new(separator: char) -> Namespace {
+        assert_ne!(separator, ESCAPE, "backslash can't be used as a separator");
+        Namespace {
+            separator,
+            prefix: String::new(),
+        }
+    }
+
+    /// The namespace `component` nested within this one.
+    pub fn namespace(&self, component: &str) -> Namespace {
+        Namespace {
+            separator: self.separator,
+            prefix: format!("{}{}", self.key(component), self.separator),
+        }
+    }
+
+    /// The key for `name` within this namespace.
+    pub fn key(&self, name: &str) -> String {
+        let mut key = self.prefix.clone();
+        for c in name.chars() {
+            if c == self.separator || c == ESCAPE {
+                key.push(ESCAPE);
+            }
+            key.push(c);
+        }
+        key
+    }
+
+    /// What every key within this namespace, including in nested ones,
+    /// starts with.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Unescaped components of `key` below this namespace, or `None` if it
+    /// isn't a key within it.
+    pub fn split(&self, key: &str) -> Option<Vec<String>> {
+        let mut chars = key.strip_prefix(&self.prefix)?.chars();
+        let mut components = vec![String::new()];
+        while let Some(c) = chars.next() {
+            match c {
+                ESCAPE => components.last_mut()?.push(chars.next()?),
+                c if c == self.separator => components.push(String::new()),
+                c => components.last_mut()?.push(c),
+            }
+        }
+        Some(components)
+    }
+}
+
+impl Default for Namespace {
+    /// The top-level namespace with `:` as the separator.
+    fn default() -> Namespace {
+        Namespace::new(':')
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn nested_keys() {
+        let user = Namespace::default().namespace("user").namespace("123");
+        assert_eq!(user.key("name"), "user:123:name");
+        assert_eq!(user.prefix(), "user:123:");
+        assert_eq!(Namespace::default().key("name"), "name");
+        assert_eq!(Namespace::new('/').namespace("a").key("b"), "a/b");
+    }
+
+    #[test]
+    fn escape_separator() {
+        let ns = Namespace::default().namespace("us:er");
+        let key = ns.key("na\\me:");
+        assert_eq!(key, "us\\:er:na\\\\me\\:");
+        // Not mistaken for the namespace "us"
+        assert!(!key.starts_with(Namespace::default().namespace("us").prefix()));
+        assert_eq!(ns.split(&key), Some(vec!["na\\me:".to_owned()]));
+        assert_eq!(
+            Namespace::default().split(&key),
+            Some(vec!["us:er".to_owned(), "na\\me:".to_owned()])
+        );
+        assert_eq!(ns.split("other:name"), None);
+        assert_eq!(Namespace::default().split("trailing\\"), None);
+    }
+}
@@ -0,0 +1,338 @@
+use std::fmt::Debug;
+use std::fs::{create_dir_all, read_dir, remove_file, rename, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+/// Extension of the write-ahead log files, which are named `{file_num}.log`
+pub(crate) const LOG_EXT: &str = "log";
+/// Extra extension of log files that are still being written by compaction
+const TMP_EXT: &str = "tmp";
+
+/// Where the numbered segments of the write-ahead log of a
+/// [KvStore](crate::KvStore) are kept. Implementations are cheap handles that
+/// are cloned to share the same underlying storage.
+pub trait LogStorage: Clone + Debug {
+    /// Open handle to a single log segment. Writes always go to the end of the
+    /// segment, regardless of the current position.
+    type Segment: Read + Write + Seek + Debug;
+
+    /// Numbers of all existing segments in ascending order.
+    fn list_segments(&self) -> io::Result<Vec<u64>>;
+
+    /// Open segment `file_num`, creating it if it doesn't exist yet.
+    fn open_segment(&self, file_num: u64) -> io::Result<Self::Segment>;
+
+    /// Open existing segment `file_num` only for reading.
+    fn read_segment(&self, file_num: u64) -> io::Result<Self::Segment>;
+
+    /// Remove segment `file_num`. Handles that are already open may continue
+    /// to read from it.
+    fn remove_segment(&self, file_num: u64) -> io::Result<()>;
+
+    /// Open an empty temporary segment that becomes segment `file_num` once
+    /// committed, discarding any left behind by an interrupted attempt.
+    /// Temporary segments are never listed.
+    fn create_temp_segment(&self, file_num: u64) -> io::Result<Self::Segment>;
+
+    /// Durably replace segment `file_num` with its temporary `segment` in a
+    /// single step, returning the handle so it can keep being used.
+    fn commit_segment(&self, file_num: u64, segment: Self::Segment) -> io::Result<Self::Segment>;
+
+    /// Directory holding the segments, if they're kept on the filesystem.
+    fn dir(&self) -> Option<&Path> {
+        None
+    }
+
+    /// Make everything written to the segments so far durable. Does nothing
+    /// by default, which suits storage that doesn't outlive the process.
+    fn sync_all(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Default [LogStorage] where each segment is a file in a directory.
+#[derive(Clone, Debug)]
+pub struct FsStorage {
+    path: PathBuf,
+}
+
+impl FsStorage {
+    /// Use the directory at `path`, creating it if necessary.
+    pub fn new(path: impl Into<PathBuf>) -> io::Result<FsStorage> {
+        let path = path.into();
+        create_dir_all(&path)?;
+        Ok(FsStorage { path })
+    }
+
+    fn segment_path(&self, file_num: u64) -> PathBuf {
+        self.path.join(format!("{}.{}", file_num, LOG_EXT))
+    }
+
+    fn temp_segment_path(&self, file_num: u64) -> PathBuf {
+        self.path
+            .join(format!("{}.{}.{}", file_num, LOG_EXT, TMP_EXT))
+    }
+
+    fn parse_file_num(file_name: &str) -> Option<u64> {
+        file_name
+            .chars()
+            .take_while(|c| c.is_ascii_digit())
+            .fold("".to_owned(), |acc, c| format!("{}{}", acc, c))
+            .parse::<u64>()
+            .ok()
+    }
+}
+
+impl LogStorage for FsStorage {
+    type Segment = File;
+
+    fn list_segments(&self) -> io::Result<Vec<u64>> {
+        let mut log_files: Vec<u64> = read_dir(&self.path)?
+            .filter_map(|fp| {
+                if let Ok(fp) = fp {
+                    let file_name = fp.file_name().into_string();
+                    match (fp.path().is_dir(), file_name) {
+                        (true, _) => None,
+                        (false, Ok(n)) if n.ends_with(&format!(".{}", LOG_EXT)) => {
+                            FsStorage::parse_file_num(&n)
+                        }
+                        _ => None,
+                    }
+                } else {
+                    None
+                }
+            })
+            .collect();
+        log_files.sort();
+        Ok(log_files)
+    }
+
+    fn open_segment(&self, file_num: u64) -> io::Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .read(true)
+            // Always append the log
+            .append(true)
+            .open(self.segment_path(file_num))
+    }
+
+    fn read_segment(&self, file_num: u64) -> io::Result<File> {
+        File::open(self.segment_path(file_num))
+    }
+
+    fn remove_segment(&self, file_num: u64) -> io::Result<()> {
+        remove_file(self.segment_path(file_num))
+    }
+
+    fn create_temp_segment(&self, file_num: u64) -> io::Result<File> {
+        let path = self.temp_segment_path(file_num);
+        // Truncate, then reopen to append like every other segment
+        File::create(&path)?;
+        OpenOptions::new().read(true).append(true).open(path)
+    }
+
+    fn commit_segment(&self, file_num: u64, segment: File) -> io::Result<File> {
+        segment.sync_all()?;
+        rename(
+            self.temp_segment_path(file_num),
+            self.segment_path(file_num),
+        )?;
+        // Make the rename itself durable
+        #[cfg(unix)]
+        File::open(&self.path)?.sync_all()?;
+        Ok(segment)
+    }
+
+    fn dir(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        for file_num in self.list_segments()? {
+            File::open(self.segment_path(file_num))?.sync_all()?;
+        }
+        // Make the creation of new segments durable too
+        #[cfg(unix)]
+        File::open(&self.path)?.sync_all()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) use mem::MemStorage;
+
+/// In-memory [LogStorage] for fast, deterministic tests that don't touch the
+/// filesystem.
+#[cfg(test)]
+mod mem {
+    use super::LogStorage;
+
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+    use std::rc::Rc;
+
+    type Data = Rc<RefCell<Vec<u8>>>;
+
+    #[derive(Clone, Debug, Default)]
+    pub(crate) struct MemStorage {
+        segments: Rc<RefCell<BTreeMap<u64, Data>>>,
+        temp_segments: Rc<RefCell<BTreeMap<u64, Data>>>,
+    }
+
+    impl MemStorage {
+        /// Current contents of segment `file_num`, if it exists.
+        pub(crate) fn segment_bytes(&self, file_num: u64) -> Option<Vec<u8>> {
+            self.segments
+                .borrow()
+                .get(&file_num)
+                .map(|data| data.borrow().clone())
+        }
+    }
+
+    #[derive(Debug)]
+    pub(crate) struct MemSegment {
+        data: Data,
+        pos: u64,
+    }
+
+    impl LogStorage for MemStorage {
+        type Segment = MemSegment;
+
+        fn list_segments(&self) -> io::Result<Vec<u64>> {
+            Ok(self.segments.borrow().keys().cloned().collect())
+        }
+
+        fn open_segment(&self, file_num: u64) -> io::Result<MemSegment> {
+            let data = self
+                .segments
+                .borrow_mut()
+                .entry(file_num)
+                .or_default()
+                .clone();
+            Ok(MemSegment { data, pos: 0 })
+        }
+
+        fn read_segment(&self, file_num: u64) -> io::Result<MemSegment> {
+            match self.segments.borrow().get(&file_num) {
+                Some(data) => Ok(MemSegment {
+                    data: data.clone(),
+                    pos: 0,
+                }),
+                None => Err(io::ErrorKind::NotFound.into()),
+            }
+        }
+
+        fn remove_segment(&self, file_num: u64) -> io::Result<()> {
+            match self.segments.borrow_mut().remove(&file_num) {
+                Some(_) => Ok(()),
+                None => Err(io::ErrorKind::NotFound.into()),
+            }
+        }
+
+        fn create_temp_segment(&self, file_num: u64) -> io::Result<MemSegment> {
+            let data = Data::default();
+            self.temp_segments
+                .borrow_mut()
+                .insert(file_num, data.clone());
+            Ok(MemSegment { data, pos: 0 })
+        }
+
+        fn commit_segment(&self, file_num: u64, segment: MemSegment) -> io::Result<MemSegment> {
+            match self.temp_segments.borrow_mut().remove(&file_num) {
+                Some(data) => {
+                    self.segments.borrow_mut().insert(file_num, data);
+                    Ok(segment)
+                }
+                None => Err(io::ErrorKind::NotFound.into()),
+            }
+        }
+    }
+
+    impl Read for MemSegment {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let data = self.data.borrow();
+            let start = (self.pos as usize).min(data.len());
+            let len = buf.len().min(data.len() - start);
+            buf[..len].copy_from_slice(&data[start..start + len]);
+            self.pos += len as u64;
+            Ok(len)
+        }
+    }
+
+    impl Write for MemSegment {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut data = self.data.borrow_mut();
+            data.extend_from_slice(buf);
+            self.pos = data.len() as u64;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for MemSegment {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            let new_pos = match pos {
+                SeekFrom::Start(offset) => Some(offset),
+                SeekFrom::End(offset) => {
+                    (self.data.borrow().len() as u64).checked_add_signed(offset)
+                }
+                SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+            };
+            match new_pos {
+                Some(new_pos) => {
+                    self.pos = new_pos;
+                    Ok(new_pos)
+                }
+                None => Err(io::ErrorKind::InvalidInput.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_good_file_num() {
+        assert_eq!(Some(100_102), FsStorage::parse_file_num("100102.log"));
+        assert_eq!(Some(0), FsStorage::parse_file_num("0.log"));
+    }
+
+    #[test]
+    fn parse_bad_file_num() {
+        assert_eq!(None, FsStorage::parse_file_num("kvs.log"));
+    }
+
+    #[test]
+    fn list_segments_only_matches_log_files() -> io::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let path = temp_dir.path().to_path_buf();
+        for name in &["2.log", "1.log", "3.txt", "log", "notes.log", "5.log.tmp"] {
+            File::create(path.join(name))?;
+        }
+        create_dir_all(path.join("4.log"))?;
+        assert_eq!(vec![1, 2], FsStorage::new(path)?.list_segments()?);
+        Ok(())
+    }
+
+    #[test]
+    fn mem_segment_appends() -> io::Result<()> {
+        let storage = MemStorage::default();
+        let mut segment = storage.open_segment(1)?;
+        segment.write_all(b"abc")?;
+        segment.seek(io::SeekFrom::Start(1))?;
+        segment.write_all(b"de")?;
+        assert_eq!(Some(b"abcde".to_vec()), storage.segment_bytes(1));
+
+        let mut buf = [0u8; 2];
+        segment.seek(io::SeekFrom::Start(1))?;
+        segment.read_exact(&mut buf)?;
+        assert_eq!(b"bc", &buf);
+        Ok(())
+    }
+}
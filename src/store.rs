@@ -1,10 +1,16 @@
-use crate::{KvsError, KvsResult};
+use crate::format::{self, LogEncoding, Op, OpIter, SegmentFormat};
+use crate::{FsStorage, KvsError, KvsResult, LogStorage};
 
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs::{create_dir_all, read_dir, remove_file, File, OpenOptions};
-use std::io::{BufWriter, Seek, SeekFrom};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::fs::{self, create_dir_all};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Bound;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 /// Key-value store where both key and value are `String`s. Uses a
 /// write-ahead log (WAL) to safely persist data to the filesystem. This also
@@ -12,120 +18,968 @@ use std::path::PathBuf;
 ///
 /// Implements periodic compaction to eliminate duplicate entries and prevent
 /// the write-ahead log from continuously growing. The compaction happens
-/// automatically once the number of opportunities has reached
-/// `COMPACTION_LIMIT`, however it can also be triggered manually by calling
-/// `KvStore::compact()`.
+/// automatically once the entries that have been overwritten or removed make
+/// up more than [KvStoreOptions::compaction_ratio] of the log, as long as
+/// there are at least `MIN_COMPACTION_BYTES` of them, or on the first write
+/// after [KvStoreOptions::compaction_interval]. It can also be triggered
+/// manually by calling `KvStore::compact()`.
+///
+/// New entries are appended to the last log file. When
+/// [KvStoreOptions::max_segment_bytes] is set, a new log file is started once
+/// the last one reaches that size so no single file grows without bound.
+/// Compaction rewrites every live entry into one new log file, or several
+/// with [KvStoreOptions::compaction_segment_bytes].
+///
+/// The log is kept in a [LogStorage], which is a directory on the filesystem
+/// unless the store is opened with [KvStore::open_with_storage].
 #[derive(Debug)]
-pub struct KvStore {
-    path: PathBuf,
-    log_file: File,
+pub struct KvStore<S: LogStorage = FsStorage> {
+    log: Log<S>,
     /// Store position and file instead of deserialized values to save memory
     index: HashMap<String, LogPtr>,
-    /// Number of opportunities for compaction, i.e. places where there are
-    /// log entries that could be eliminated
-    compactions: u16,
-    /// max id of current log files
-    monotonic: u64,
+    /// Position after the last entry replayed from each log file, so a
+    /// follower can continue from there
+    read_to: HashMap<u64, u64>,
+    /// Size of all entries in the log
+    total_bytes: u64,
+    /// Size of the entries that have been superseded and would be eliminated
+    /// by compaction
+    dead_bytes: u64,
+    /// Size at which compaction starts a new log file
+    compaction_segment_bytes: Option<u64>,
+    /// Fraction of dead bytes above which to compact
+    compaction_ratio: f64,
+    /// How often to compact if there's anything to eliminate at all
+    compaction_interval: Option<Duration>,
+    /// When the store was opened or last compacted
+    last_compaction: Instant,
+    /// What `get` does with entries that can't be decoded
+    get_error_policy: GetErrorPolicy,
+    /// Longest key accepted, if keys are validated
+    max_key_len: Option<usize>,
+    /// Largest `total_bytes` writes may grow the log to
+    max_total_bytes: Option<u64>,
+    /// Keys of `index` in sorted order, if prefix scans are indexed
+    prefix_index: Option<BTreeSet<String>>,
+    /// How long removals are kept through compaction, if at all
+    tombstone_retention: Option<Duration>,
+    /// When each removed key was removed. Only tracked when removals are
+    /// retained
+    tombstones: HashMap<String, u64>,
+    /// Called after every compaction
+    on_compaction: CompactionHook,
+    /// Operations since the store was created
+    stats: Stats,
+    /// `stats` as last saved or loaded
+    saved_stats: Stats,
+    /// Operations counted in `stats` since it was last saved
+    unsaved_ops: u64,
+}
+
+impl<S: LogStorage> Drop for KvStore<S> {
+    fn drop(&mut self) {
+        // Reads alone aren't worth writing to the data directory for, so a
+        // store that's only read from, like `kvs get`, leaves it untouched
+        if self.stats.sets != self.saved_stats.sets
+            || self.stats.removes != self.saved_stats.removes
+        {
+            let _ = self.save_stats();
+        }
+    }
+}
+
+/// Read-only view of a [KvStore] at the time [KvStore::snapshot] was called.
+#[derive(Debug)]
+pub struct Snapshot<S: LogStorage = FsStorage> {
+    /// Never has an active segment, so it can't be written to
+    log: Log<S>,
+    index: HashMap<String, LogPtr>,
+    /// Log files the index points into
+    pinned: BTreeSet<u64>,
+}
+
+impl<S: LogStorage> Snapshot<S> {
+    /// Get the value `key` had when the snapshot was taken.
+    pub fn get(&mut self, key: &str) -> KvsResult<Option<String>> {
+        match self.index.get(key) {
+            Some(log_ptr) => self.log.read_value(log_ptr).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// List the keys in the snapshot in sorted order, optionally only those
+    /// starting with `prefix`.
+    pub fn keys(&self, prefix: Option<&str>) -> Vec<String> {
+        sorted_keys(&self.index, prefix)
+    }
+}
+
+impl<S: LogStorage> Drop for Snapshot<S> {
+    fn drop(&mut self) {
+        {
+            let mut pins = self.log.pins.lock().unwrap();
+            for file_num in &self.pinned {
+                if let Some(count) = pins.counts.get_mut(file_num) {
+                    *count -= 1;
+                    if *count == 0 {
+                        pins.counts.remove(file_num);
+                    }
+                }
+            }
+        }
+        // Any errors are left for the next compaction to run into
+        let _ = self.log.remove_superseded();
+    }
+}
+
+/// Every entry of a [Snapshot] in key order, see [KvStore::full_scan_sorted].
+#[derive(Debug)]
+pub struct SortedScan<S: LogStorage = FsStorage> {
+    snapshot: Snapshot<S>,
+    keys: std::vec::IntoIter<String>,
+}
+
+impl<S: LogStorage> Iterator for SortedScan<S> {
+    type Item = KvsResult<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let key = self.keys.next()?;
+        // Every key listed is in the snapshot's index
+        Some(match self.snapshot.get(&key) {
+            Ok(Some(value)) => Ok((key, value)),
+            Ok(None) => Err(KvsError::KeyNotFound { key }),
+            Err(err) => Err(err),
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.keys.size_hint()
+    }
+}
+
+/// Summary of a completed compaction, passed to the callback registered with
+/// [KvStore::on_compaction].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// Numbers of the log files that were removed
+    pub old_file_nums: Vec<u64>,
+    /// Number of the last log file that replaced them, which new entries are
+    /// appended to. See [KvStoreOptions::compaction_segment_bytes]
+    pub new_file_num: u64,
+    /// Number of live keys rewritten to the new log file
+    pub keys_retained: usize,
+    /// Combined size of the old log files less the size of the new one
+    pub bytes_reclaimed: u64,
+}
+
+/// Result of the quick self-check done by [KvStore::health].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Health {
+    pub ok: bool,
+    /// What was found, for people
+    pub detail: String,
+}
+
+/// Entries in key order returned by [KvStore::scan_page].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScanPage {
+    pub entries: Vec<(String, String)>,
+    /// Last key returned, to pass as the cursor for the next page. `None` once
+    /// there are no more entries
+    pub next_cursor: Option<String>,
+}
+
+/// Point in the log returned by [KvStore::checkpoint].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Number of the active log file
+    pub file_num: u64,
+    /// Size of the active log file, i.e. where the next entry will be written
+    pub offset: u64,
+}
+
+/// An entry in the log as returned by [KvStore::recent_ops].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpSummary {
+    pub kind: OpKind,
+    pub key: String,
+}
+
+/// What an entry in the log did to its key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpKind {
+    Set,
+    Remove,
+}
+
+/// Number of operations done on a store since it was created, see
+/// [KvStore::stats].
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Stats {
+    pub sets: u64,
+    pub gets: u64,
+    pub removes: u64,
+}
+
+/// How a write with [KvStore::set_reporting] grew the log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriteReport {
+    /// Size of the entry appended to the log
+    pub bytes_written: u64,
+    /// Combined size of the entries in the log after the write, and after
+    /// compacting if it triggered compaction
+    pub total_log_bytes: u64,
+    /// Whether the write compacted the log
+    pub triggered_compaction: bool,
+}
+
+/// Size of a store, see [KvStore::disk_usage].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// Combined size of all log files
+    pub total_log_bytes: u64,
+    /// Size of the log entries of the current values. This includes the keys
+    /// and encoding overhead, so it's what a compacted log would take up
+    /// rather than the size of the values alone
+    pub live_value_bytes_estimate: u64,
+    /// Number of log files
+    pub num_segments: usize,
+}
+
+/// Result of checking the index against the log with [KvStore::verify].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Number of keys whose entries were read
+    pub keys_checked: usize,
+    /// Keys whose entry in the log isn't what the index says it is, sorted by
+    /// key
+    pub mismatches: Vec<IndexMismatch>,
+}
+
+impl VerifyReport {
+    /// Whether every key in the index points at its own entry.
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// An index entry that doesn't point at a set of its key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexMismatch {
+    pub key: String,
+    pub file_num: u64,
+    pub pos: u64,
+    /// What was found instead
+    pub reason: String,
+}
+
+type CompactionCallback = Box<dyn Fn(&CompactionReport) + Send + Sync>;
+
+/// Wrapper so [KvStore] can still derive `Debug`
+#[derive(Default)]
+struct CompactionHook(Option<CompactionCallback>);
+
+impl fmt::Debug for CompactionHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(_) => write!(f, "Some(<callback>)"),
+            None => write!(f, "None"),
+        }
+    }
+}
+
+/// Options for opening a [KvStore].
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    /// Encoding of new log entries. Existing log files keep the encoding they
+    /// were written with, so a directory may contain a mix of encodings.
+    pub encoding: LogEncoding,
+    /// By default compaction drops every removal from the log. When set,
+    /// removals younger than this are kept so a consumer of the log can still
+    /// learn that a key was deleted.
+    pub tombstone_retention: Option<Duration>,
+    /// Size in bytes at which to start a new log file instead of appending to
+    /// the current one. Unlimited by default.
+    pub max_segment_bytes: Option<u64>,
+    /// Size in bytes at which compaction starts a new log file, so a large
+    /// store doesn't end up in one enormous file. By default compaction
+    /// writes a single log file.
+    pub compaction_segment_bytes: Option<u64>,
+    /// Fraction of the log that must be overwritten or removed entries before
+    /// compacting automatically. Defaults to `0.5`.
+    pub compaction_ratio: f64,
+    /// When set, a write also compacts if this long has passed since the
+    /// store was opened or last compacted and any entries are dead, however
+    /// few. There's no background thread, so an idle store isn't compacted.
+    pub compaction_interval: Option<Duration>,
+    /// What [KvStore::get] does when the entry for a key can't be decoded.
+    pub get_error_policy: GetErrorPolicy,
+    /// When set, keys written or removed are rejected with
+    /// [KvsError::InvalidKey] if they're empty, contain control characters or
+    /// are longer than this many bytes. Any key is accepted by default.
+    pub max_key_len: Option<usize>,
+    /// When set, writes that would make the entries in the log take up more
+    /// than this many bytes, even after compacting, fail with
+    /// [KvsError::StorageFull]. Removals are always allowed so space can be
+    /// freed up. Unlimited by default.
+    pub max_total_bytes: Option<u64>,
+    /// Keep the keys sorted alongside the index so [KvStore::keys],
+    /// [KvStore::scan_page] and [KvStore::count_prefix] only visit the keys
+    /// matching the prefix instead of every key in the store. Costs a second
+    /// copy of every key in memory and some time on each write, so it's off
+    /// by default.
+    pub enable_prefix_index: bool,
+    /// Write the key of each set only the first time it's set in a log file,
+    /// referring to it by a small id after that. Saves space when long keys
+    /// are overwritten often, but makes the log harder to inspect and sets of
+    /// new keys slightly larger. Compaction writes every key once anyway, so
+    /// it doesn't intern them. Off by default.
+    pub intern_keys: bool,
+}
+
+/// How [KvStore::get] handles an entry in the log that can't be decoded,
+/// e.g. because it was corrupted on disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GetErrorPolicy {
+    /// Return the decoding error
+    #[default]
+    Propagate,
+    /// Return `Ok(None)` as if the key didn't exist, which suits using the
+    /// store as a cache
+    TreatCorruptAsMissing,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            encoding: LogEncoding::default(),
+            tombstone_retention: None,
+            max_segment_bytes: None,
+            compaction_segment_bytes: None,
+            compaction_ratio: 0.5,
+            compaction_interval: None,
+            get_error_policy: GetErrorPolicy::default(),
+            max_key_len: None,
+            max_total_bytes: None,
+            enable_prefix_index: false,
+            intern_keys: false,
+        }
+    }
 }
 
-/// Arbitrary limit before compacting. Could be made configurable or experiment
-/// to find good number
-static COMPACTION_LIMIT: u16 = 50;
+/// Number of entries [KvStore::bulk_set] writes at a time
+const BULK_CHUNK_LEN: usize = 1024;
+
+/// Dead bytes below which compacting isn't worth it, regardless of the ratio
+static MIN_COMPACTION_BYTES: u64 = 4096;
+
+/// File in the data directory where [Stats] are saved
+const STATS_FILE: &str = "stats.json";
+
+/// Number of operations after which [Stats] are saved
+const STATS_SAVE_INTERVAL: u64 = 1024;
 
 impl KvStore {
     /// Open the database at `path`. To create a new database `path` should be
     /// an empty directory.
     pub fn open(path: impl Into<PathBuf>) -> KvsResult<KvStore> {
-        let path = path.into();
-        create_dir_all(&path)?;
-        let log_file_nums = KvStore::sorted_file_nums(&path)?;
-
-        // Build index
-        let mut index = HashMap::new();
-        let mut compactions = 0u16;
-        let monotonic = if log_file_nums.is_empty() {
-            1
-        } else {
-            // `fold` files together
-            for file_num in &log_file_nums {
-                let mut log_file = KvStore::open_file(&path.join(format!("{}.log", file_num)))?;
-                loop {
-                    let pos = KvStore::current_pos(&mut log_file)?;
-                    if let Ok(op) = bincode::deserialize_from(&log_file) {
-                        match op {
-                            Op::Set { key, .. } => {
-                                if index
-                                    .insert(
-                                        key,
-                                        LogPtr {
-                                            file_num: file_num.to_owned(),
-                                            pos,
-                                        },
-                                    )
-                                    .is_some()
-                                {
-                                    // `key` previously existed in `index`. This is an
-                                    // opportunity for compaction
-                                    compactions += 1;
-                                }
-                            }
-                            Op::Rm { key } => {
-                                index.remove(&key);
-                                compactions += 1;
-                            }
-                        };
-                    } else {
+        KvStore::open_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Open the database at `path` with non-default `options`.
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+    ) -> KvsResult<KvStore> {
+        KvStore::open_with_storage(FsStorage::new(path)?, options)
+    }
+
+    /// Open the database at `path` as a read-only follower of the store
+    /// writing to it, see [KvStore::open_follower_with_storage].
+    pub fn open_follower(path: impl Into<PathBuf>) -> KvsResult<KvStore> {
+        KvStore::open_follower_with_storage(FsStorage::new(path)?, KvStoreOptions::default())
+    }
+}
+
+impl<S: LogStorage> KvStore<S> {
+    /// Open the database kept in `storage`.
+    pub fn open_with_storage(storage: S, options: KvStoreOptions) -> KvsResult<KvStore<S>> {
+        let mut store = KvStore::unopened(storage, options);
+        store.replay()?;
+        let mut monotonic = store.log.monotonic.max(1);
+        let current_format = SegmentFormat::current(store.log.encoding);
+        // Entries appended after one that was cut off, e.g. the rest of a
+        // batch lost in a crash, would never be replayed
+        let cut_off = match store.read_to.get(&monotonic) {
+            Some(read_to) => {
+                store
+                    .log
+                    .storage
+                    .read_segment(monotonic)?
+                    .seek(SeekFrom::End(0))?
+                    > *read_to
+            }
+            None => false,
+        };
+        if cut_off
+            || store
+                .log
+                .formats
+                .get(&monotonic)
+                .is_some_and(|format| *format != current_format)
+        {
+            // Leave the active log file as it is and start a new one
+            monotonic += 1;
+        }
+        let mut active = store.log.storage.open_segment(monotonic)?;
+        store.log.formats.insert(
+            monotonic,
+            format::init_segment(&mut active, store.log.encoding)?,
+        );
+        store.log.active = Some(active);
+        store.log.monotonic = monotonic;
+        if store.log.key_ids.file_num != monotonic {
+            store.log.reset_key_ids(monotonic);
+        }
+        Ok(store)
+    }
+
+    /// Open the database kept in `storage` as a read-only follower of the
+    /// store writing to it. Nothing is ever written to `storage`, and entries
+    /// written since opening are picked up by [KvStore::refresh].
+    pub fn open_follower_with_storage(
+        storage: S,
+        options: KvStoreOptions,
+    ) -> KvsResult<KvStore<S>> {
+        let mut store = KvStore::unopened(storage, options);
+        store.replay()?;
+        Ok(store)
+    }
+
+    /// Store with an empty index and no active log file.
+    fn unopened(storage: S, options: KvStoreOptions) -> KvStore<S> {
+        KvStore {
+            log: Log {
+                storage,
+                active: None,
+                monotonic: 0,
+                encoding: options.encoding,
+                formats: HashMap::new(),
+                max_segment_bytes: options.max_segment_bytes,
+                intern_keys: options.intern_keys,
+                key_ids: KeyIds::default(),
+                pins: Arc::default(),
+            },
+            index: HashMap::new(),
+            read_to: HashMap::new(),
+            total_bytes: 0,
+            dead_bytes: 0,
+            compaction_segment_bytes: options.compaction_segment_bytes,
+            compaction_ratio: options.compaction_ratio,
+            compaction_interval: options.compaction_interval,
+            last_compaction: Instant::now(),
+            get_error_policy: options.get_error_policy,
+            max_key_len: options.max_key_len,
+            max_total_bytes: options.max_total_bytes,
+            prefix_index: options.enable_prefix_index.then(BTreeSet::new),
+            tombstone_retention: options.tombstone_retention,
+            tombstones: HashMap::new(),
+            on_compaction: CompactionHook::default(),
+            stats: Stats::default(),
+            saved_stats: Stats::default(),
+            unsaved_ops: 0,
+        }
+        .with_saved_stats()
+    }
+
+    /// Load the [Stats] saved in the data directory. A missing or unreadable
+    /// file starts the counts over.
+    fn with_saved_stats(mut self) -> KvStore<S> {
+        if let Some(dir) = self.log.storage.dir() {
+            self.stats = fs::read(dir.join(STATS_FILE))
+                .ok()
+                .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+                .unwrap_or_default();
+            self.saved_stats = self.stats;
+        }
+        self
+    }
+
+    /// Count operations with `count`, saving the [Stats] every so often.
+    fn count(&mut self, count: impl FnOnce(&mut Stats)) {
+        count(&mut self.stats);
+        self.unsaved_ops += 1;
+        if self.unsaved_ops >= STATS_SAVE_INTERVAL {
+            // The counts are only for analytics, so losing some of them is
+            // better than failing the operation
+            let _ = self.save_stats();
+        }
+    }
+
+    /// Write the [Stats] to the data directory, replacing the file in a single
+    /// step. Followers don't save their counts.
+    fn save_stats(&mut self) -> KvsResult<()> {
+        if let (Some(dir), Some(_)) = (self.log.storage.dir(), &self.log.active) {
+            let tmp_path = dir.join(format!("{}.tmp", STATS_FILE));
+            fs::write(&tmp_path, serde_json::to_vec(&self.stats)?)?;
+            fs::rename(tmp_path, dir.join(STATS_FILE))?;
+        }
+        self.saved_stats = self.stats;
+        self.unsaved_ops = 0;
+        Ok(())
+    }
+
+    /// Operations done on the store since it was created, including in
+    /// earlier processes. The counts are saved to the data directory every
+    /// 1024 operations and when a store that was written to is dropped, so a
+    /// crash, or dropping a store that was only read from, may lose the most
+    /// recent ones. Stores not kept on the filesystem start over each
+    /// time they're opened.
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Apply the entries of every log file to the index, continuing from
+    /// where the previous replay stopped in each file.
+    fn replay(&mut self) -> KvsResult<()> {
+        let file_nums = self.log.storage.list_segments()?;
+        // Log files are only ever added after the last one and removed from
+        // the front, so a gap means some were lost, e.g. in a botched restore
+        if let Some(gap) = file_nums.windows(2).find(|pair| pair[1] != pair[0] + 1) {
+            return Err(KvsError::Corruption {
+                reason: format!("log files {} to {} are missing", gap[0] + 1, gap[1] - 1),
+            });
+        }
+        // `fold` files together
+        for file_num in file_nums {
+            self.log.monotonic = file_num;
+            let mut log_file = self.log.storage.read_segment(file_num)?;
+            let (format, start) = match self.read_to.get(&file_num) {
+                // Only the last log file replayed can have grown since, so
+                // the keys interned in it are still the ones kept
+                Some(pos) => (self.log.formats[&file_num], *pos),
+                None => match format::read_header(&mut log_file, file_num)? {
+                    Some(header) => {
+                        self.log.reset_key_ids(file_num);
+                        header
+                    }
+                    None => continue,
+                },
+            };
+            self.log.formats.insert(file_num, format);
+            let mut ops = OpIter::new(log_file, format, start)?;
+            let mut read_to = start;
+            while let Some((pos, op)) = ops.next() {
+                let mut entries = vec![(pos, ops.pos() - pos, op)];
+                if let Op::Batch { count, .. } = entries[0].2 {
+                    while entries.len() as u64 <= count {
+                        match ops.next() {
+                            Some((pos, op)) => entries.push((pos, ops.pos() - pos, op)),
+                            None => break,
+                        }
+                    }
+                    // The rest of the batch hasn't been written, at least not
+                    // yet, so continue from the start of it next time
+                    if entries.len() as u64 <= count {
                         break;
                     }
                 }
+                for (pos, len, op) in entries {
+                    self.apply(file_num, pos, len, op)?;
+                }
+                read_to = ops.pos();
             }
-            log_file_nums.last().unwrap().to_owned()
-        };
-        Ok(KvStore {
-            log_file: KvStore::open_file(&path.join(format!("{}.log", monotonic)))?,
-            path,
-            index,
-            compactions,
-            monotonic,
-        })
+            self.read_to.insert(file_num, read_to);
+        }
+        Ok(())
+    }
+
+    /// Apply the entry `op` written to log file `file_num` to the index.
+    fn apply(&mut self, file_num: u64, pos: u64, len: u64, op: Op) -> KvsResult<()> {
+        match op {
+            Op::Set { key, unix_ms, .. } => {
+                let log_ptr = LogPtr {
+                    file_num,
+                    pos,
+                    len,
+                    unix_ms,
+                };
+                self.insert(key, log_ptr);
+            }
+            Op::Rm { key, unix_ms } => self.removed(key, len, unix_ms),
+            Op::SetById {
+                key_id, unix_ms, ..
+            } => {
+                let key = match self.log.key_ids.keys.get(&key_id) {
+                    Some(key) => key.clone(),
+                    None => {
+                        return Err(KvsError::Corruption {
+                            reason: format!(
+                                "log file {} sets undefined key id {} at {}",
+                                file_num, key_id, pos
+                            ),
+                        })
+                    }
+                };
+                let log_ptr = LogPtr {
+                    file_num,
+                    pos,
+                    len,
+                    unix_ms,
+                };
+                self.insert(key, log_ptr);
+            }
+            // Only needed until its entries have been rewritten by compaction
+            Op::Batch { .. } => {
+                self.total_bytes += len;
+                self.dead_bytes += len;
+            }
+            Op::DefineKey { key_id, key } => {
+                self.total_bytes += len;
+                self.dead_bytes += len;
+                if self.log.intern_keys {
+                    self.log.key_ids.ids.insert(key.clone(), key_id);
+                }
+                self.log.key_ids.keys.insert(key_id, key);
+            }
+        }
+        Ok(())
+    }
+
+    /// Pick up entries written to the log since the follower was opened or
+    /// last refreshed. If the writer compacted in the meantime, the index is
+    /// rebuilt from scratch. Does nothing for a store that isn't a follower.
+    pub fn refresh(&mut self) -> KvsResult<()> {
+        if self.log.active.is_some() {
+            return Ok(());
+        }
+        let file_nums = self.log.storage.list_segments()?;
+        if self
+            .log
+            .formats
+            .keys()
+            .any(|file_num| !file_nums.contains(file_num))
+        {
+            self.index.clear();
+            if let Some(prefix_index) = &mut self.prefix_index {
+                prefix_index.clear();
+            }
+            self.tombstones.clear();
+            self.read_to.clear();
+            self.log.formats.clear();
+            self.log.reset_key_ids(0);
+            self.total_bytes = 0;
+            self.dead_bytes = 0;
+        }
+        self.replay()
+    }
+
+    /// Followers can't be written to.
+    fn check_writable(&self) -> KvsResult<()> {
+        match self.log.active {
+            Some(_) => Ok(()),
+            None => Err(KvsError::ReadOnly),
+        }
     }
 
     /// Set the value of `key` to `value`. Overwrites any existing entry for
     /// `key`.
     pub fn set(&mut self, key: String, value: String) -> KvsResult<()> {
+        self.set_reporting(key, value).map(|_| ())
+    }
+
+    /// Like [KvStore::set], but also reports how much the write grew the log,
+    /// so a writer can throttle itself before the log balloons.
+    pub fn set_reporting(&mut self, key: String, value: String) -> KvsResult<WriteReport> {
+        self.set_flagged(key, value, 0)
+    }
+
+    /// Like [KvStore::set], but also stores `flags` alongside the value for
+    /// the client's own use, e.g. as a hint of the value's type. They're
+    /// returned by [KvStore::get_with_flags] and kept by [KvStore::rename].
+    /// Every other way of setting a value sets the flags to 0.
+    pub fn set_with_flags(&mut self, key: String, value: String, flags: u32) -> KvsResult<()> {
+        self.set_flagged(key, value, flags).map(|_| ())
+    }
+
+    fn set_flagged(&mut self, key: String, value: String, flags: u32) -> KvsResult<WriteReport> {
+        self.check_writable()?;
+        self.validate_key(&key)?;
         // Log
         let op = Op::Set {
-            key: key.clone(),
+            key,
             value,
+            unix_ms: format::now_ms(),
+            flags,
         };
-        let pos = self.log_file.seek(SeekFrom::End(0))?;
-        let writer = BufWriter::new(&self.log_file);
-        bincode::serialize_into(writer, &op)?;
-        // Set
-        if self
-            .index
-            .insert(
-                key,
-                LogPtr {
-                    file_num: self.monotonic,
-                    pos,
-                },
-            )
-            .is_some()
-        {
-            // Compaction
-            self.compactions += 1;
+        self.make_room(std::slice::from_ref(&op))?;
+        let bytes_written = self.write(vec![op])?;
+        // Compaction
+        let triggered_compaction = self.compact_maybe()?;
+        Ok(WriteReport {
+            bytes_written,
+            total_log_bytes: self.total_bytes,
+            triggered_compaction,
+        })
+    }
+
+    /// Set the value of `key` to `value` only if there's no entry for `key`
+    /// yet. Returns whether the entry was created.
+    pub fn set_nx(&mut self, key: String, value: String) -> KvsResult<bool> {
+        self.check_writable()?;
+        self.validate_key(&key)?;
+        if self.index.contains_key(&key) {
+            return Ok(false);
+        }
+        self.set(key, value)?;
+        Ok(true)
+    }
+
+    /// Set many entries at once, as if by calling [KvStore::set] for each in
+    /// order. Entries are written in chunks through a single buffer, which is
+    /// much faster than separate calls when loading lots of data. Keys are
+    /// checked a chunk at a time, so if one is invalid or writing fails part
+    /// way through, the chunks before it are kept. Either way, the entries
+    /// written are durable once it returns.
+    pub fn bulk_set(
+        &mut self,
+        entries: impl IntoIterator<Item = (String, String)>,
+    ) -> KvsResult<()> {
+        self.check_writable()?;
+        let written = self.write_chunks(entries);
+        let synced = self
+            .log
+            .storage
+            .sync_all()
+            .map_err(|err| self.log.dir_missing(err.into()));
+        written.and(synced)
+    }
+
+    /// Write `entries` in chunks of [BULK_CHUNK_LEN], see [KvStore::bulk_set].
+    fn write_chunks(
+        &mut self,
+        entries: impl IntoIterator<Item = (String, String)>,
+    ) -> KvsResult<()> {
+        let unix_ms = format::now_ms();
+        let mut entries = entries.into_iter().peekable();
+        while entries.peek().is_some() {
+            let ops: Vec<Op> = entries
+                .by_ref()
+                .take(BULK_CHUNK_LEN)
+                .map(|(key, value)| {
+                    self.validate_key(&key)?;
+                    Ok(Op::Set {
+                        key,
+                        value,
+                        unix_ms,
+                        flags: 0,
+                    })
+                })
+                .collect::<KvsResult<_>>()?;
+            self.make_room(&ops)?;
+            self.write(ops)?;
             self.compact_maybe()?;
         }
         Ok(())
     }
 
+    /// Set all of `entries` together, later entries for the same key winning.
+    /// They're written to the log as a single batch, so even after a crash
+    /// either all or none of them are in the store, and followers never pick
+    /// up only some of them. Nothing is written if any key is invalid. Holds
+    /// all of `entries` in memory.
+    pub fn multi_set(
+        &mut self,
+        entries: impl IntoIterator<Item = (String, String)>,
+    ) -> KvsResult<()> {
+        self.check_writable()?;
+        let unix_ms = format::now_ms();
+        let mut ops = vec![Op::Batch { count: 0, unix_ms }];
+        for (key, value) in entries {
+            self.validate_key(&key)?;
+            ops.push(Op::Set {
+                key,
+                value,
+                unix_ms,
+                flags: 0,
+            });
+        }
+        if ops.len() == 1 {
+            return Ok(());
+        }
+        ops[0] = Op::Batch {
+            count: ops.len() as u64 - 1,
+            unix_ms,
+        };
+        self.make_room(&ops)?;
+        self.write(ops)?;
+        self.compact_maybe()?;
+        Ok(())
+    }
+
+    /// Append `ops` to the log and apply them to the index, returning the
+    /// combined size of the entries written.
+    fn write(&mut self, ops: Vec<Op>) -> KvsResult<u64> {
+        let written = self.log.append_interned(ops)?;
+        let file_num = self.log.monotonic;
+        let mut bytes = 0;
+        for (pos, len, op) in written {
+            if let Op::Set { .. } | Op::SetById { .. } = op {
+                self.count(|stats| stats.sets += 1);
+            }
+            bytes += len;
+            self.apply(file_num, pos, len, op)?;
+        }
+        Ok(bytes)
+    }
+
+    /// Check `key` against the validation enabled in the options, if any.
+    fn validate_key(&self, key: &str) -> KvsResult<()> {
+        let max_key_len = match self.max_key_len {
+            Some(max_key_len) => max_key_len,
+            None => return Ok(()),
+        };
+        let reason = if key.is_empty() {
+            "key is empty".to_owned()
+        } else if key.len() > max_key_len {
+            format!("key is {} bytes, more than {}", key.len(), max_key_len)
+        } else if key.chars().any(char::is_control) {
+            "key contains a control character".to_owned()
+        } else {
+            return Ok(());
+        };
+        Err(KvsError::InvalidKey { reason })
+    }
+
+    /// Drop `key` from the index after its removal of `len` bytes was written.
+    fn removed(&mut self, key: String, len: u64, unix_ms: u64) {
+        self.total_bytes += len;
+        // A removal retained through compaction doesn't shadow anything
+        if let Some(old) = self.index.remove(&key) {
+            self.dead_bytes += old.len;
+            if let Some(prefix_index) = &mut self.prefix_index {
+                prefix_index.remove(&key);
+            }
+        }
+        if self.tombstone_retention.is_some() {
+            self.tombstones.insert(key, unix_ms);
+        } else {
+            self.dead_bytes += len;
+        }
+    }
+
+    /// Make sure `ops` can be written without exceeding `max_total_bytes`,
+    /// compacting first if that frees up enough space.
+    fn make_room(&mut self, ops: &[Op]) -> KvsResult<()> {
+        let max_total_bytes = match self.max_total_bytes {
+            Some(max_total_bytes) => max_total_bytes,
+            None => return Ok(()),
+        };
+        let mut len = self.log.appended_len(ops)?;
+        if self.total_bytes + len > max_total_bytes && self.dead_bytes > 0 {
+            self.compact()?;
+            // Compaction changes which keys are interned
+            len = self.log.appended_len(ops)?;
+        }
+        if self.total_bytes + len > max_total_bytes {
+            return Err(KvsError::StorageFull { max_total_bytes });
+        }
+        Ok(())
+    }
+
+    /// Point `key` at its newly written entry.
+    fn insert(&mut self, key: String, log_ptr: LogPtr) {
+        self.total_bytes += log_ptr.len;
+        self.tombstones.remove(&key);
+        if let Some(prefix_index) = &mut self.prefix_index {
+            if !self.index.contains_key(&key) {
+                prefix_index.insert(key.clone());
+            }
+        }
+        if let Some(old) = self.index.insert(key, log_ptr) {
+            // `key` previously existed in `index`, so its old entry can be
+            // eliminated by compaction
+            self.dead_bytes += old.len;
+        }
+    }
+
     /// Get the value associated with `key`. Returns `Some(value)` if the entry
-    // exists, otherwise `None`
+    /// exists, otherwise `None`. See [GetErrorPolicy] for entries that can't
+    /// be decoded.
     pub fn get(&mut self, key: String) -> KvsResult<Option<String>> {
-        match self.index.get(&key) {
-            Some(log_ptr) => KvStore::value_at_pos(&self.log_file, log_ptr.pos).map(Some),
-            None => Ok(None),
+        self.count(|stats| stats.gets += 1);
+        let log_ptr = match self.index.get(&key) {
+            Some(log_ptr) => log_ptr,
+            None => return Ok(None),
+        };
+        let value = self.log.read_value(log_ptr);
+        self.apply_get_error_policy(value)
+    }
+
+    /// Get the value associated with `key` along with the flags it was set
+    /// with, see [KvStore::set_with_flags].
+    pub fn get_with_flags(&mut self, key: String) -> KvsResult<Option<(String, u32)>> {
+        self.count(|stats| stats.gets += 1);
+        let log_ptr = match self.index.get(&key) {
+            Some(log_ptr) => log_ptr,
+            None => return Ok(None),
+        };
+        let entry = self
+            .log
+            .read_entry(log_ptr)
+            .map(|(value, _, flags)| (value, flags));
+        self.apply_get_error_policy(entry)
+    }
+
+    /// Get the value associated with `key`, or `default` if there is none.
+    pub fn get_or(&mut self, key: String, default: String) -> KvsResult<String> {
+        Ok(self.get(key)?.unwrap_or(default))
+    }
+
+    /// Get the value associated with `key`, first setting it to `default` if
+    /// there is none.
+    pub fn get_or_set(&mut self, key: String, default: String) -> KvsResult<String> {
+        self.check_writable()?;
+        match self.get(key.clone())? {
+            Some(value) => Ok(value),
+            None => {
+                self.set(key, default.clone())?;
+                Ok(default)
+            }
+        }
+    }
+
+    /// Get at most `len` bytes of the value associated with `key`, starting
+    /// `offset` bytes into it. A range past the end of the value is empty.
+    /// Only the range is read from bincode-encoded log files, so this is
+    /// cheaper than [get](KvStore::get) for large values. Returns
+    /// `Err(KvsError::InvalidRange)` if the range would split a character.
+    pub fn get_range(&mut self, key: String, offset: u64, len: u64) -> KvsResult<Option<String>> {
+        self.count(|stats| stats.gets += 1);
+        let log_ptr = match self.index.get(&key) {
+            Some(log_ptr) => log_ptr,
+            None => return Ok(None),
+        };
+        let range = self.log.read_value_range(&key, log_ptr, offset, len);
+        let bytes = match self.apply_get_error_policy(range)? {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+        String::from_utf8(bytes)
+            .map(Some)
+            .map_err(|_| KvsError::InvalidRange { key, offset, len })
+    }
+
+    /// Handle errors reading an entry according to the [GetErrorPolicy].
+    fn apply_get_error_policy<T>(&self, res: KvsResult<T>) -> KvsResult<Option<T>> {
+        match res {
+            Ok(value) => Ok(Some(value)),
+            Err(KvsError::Serialization { .. }) | Err(KvsError::Json { .. })
+                if self.get_error_policy == GetErrorPolicy::TreatCorruptAsMissing =>
+            {
+                Ok(None)
+            }
+            Err(err) => Err(err),
         }
     }
 
@@ -133,139 +987,2128 @@ impl KvStore {
     /// there is no entry for `key`.
     pub fn remove(&mut self, key: String) -> KvsResult<()> {
         // Error checking
+        self.check_writable()?;
+        self.validate_key(&key)?;
         if !self.index.contains_key(&key) {
             return Err(KvsError::KeyNotFound { key });
         }
+        self.remove_existing(key)
+    }
+
+    /// Remove every entry whose key starts with `prefix`, returning how many
+    /// were removed. Each removal is logged separately, so a crash part way
+    /// through may leave some of the keys in place.
+    pub fn remove_prefix(&mut self, prefix: &str) -> KvsResult<usize> {
+        self.check_writable()?;
+        let keys = self.keys(Some(prefix));
+        let removed = keys.len();
+        for key in keys {
+            self.remove_existing(key)?;
+        }
+        Ok(removed)
+    }
+
+    /// Log the removal of `key`, which must be in the index.
+    fn remove_existing(&mut self, key: String) -> KvsResult<()> {
         // Log
-        let op = Op::Rm { key: key.clone() };
-        let writer = BufWriter::new(&self.log_file);
-        bincode::serialize_into(writer, &op)?;
+        let unix_ms = format::now_ms();
+        let op = Op::Rm {
+            key: key.clone(),
+            unix_ms,
+        };
+        let (_, len) = self.log.append(&op)?;
+        self.count(|stats| stats.removes += 1);
         // Remove
-        self.index.remove(&key);
+        self.removed(key, len, unix_ms);
         // Compaction
-        self.compactions += 1;
         self.compact_maybe()?;
         Ok(())
     }
 
-    fn compact_maybe(&mut self) -> KvsResult<()> {
-        if self.compactions >= COMPACTION_LIMIT {
-            self.compact()
-        } else {
-            Ok(())
+    /// Move the value and flags of `from` to `to`, overwriting any existing
+    /// entry for `to`. Returns `Err(KvsError::KeyNotFound)` if there is no
+    /// entry for `from`. The new entry is logged before the removal, so a
+    /// crash in between can leave both keys set but never loses the value.
+    pub fn rename(&mut self, from: String, to: String) -> KvsResult<()> {
+        // Both must be valid before anything is written
+        self.check_writable()?;
+        self.validate_key(&from)?;
+        self.validate_key(&to)?;
+        let (value, flags) = match self.get_with_flags(from.clone())? {
+            Some(entry) => entry,
+            None => return Err(KvsError::KeyNotFound { key: from }),
+        };
+        if from == to {
+            return Ok(());
         }
+        self.set_flagged(to, value, flags)?;
+        self.remove(from)
     }
 
-    /// Forces compaction. Rewrites log, eliminating unnecessary logs, i.e.
-    /// removals and sets that are overwritten later.
-    pub fn compact(&mut self) -> KvsResult<()> {
-        let mut new_log =
-            KvStore::open_file(&self.path.join(format!("{}.log", self.monotonic + 1)))?;
-        for (key, log_ptr) in &mut self.index {
-            // Even if we error out writing these, the data will not be
-            // corrupted
-            let value = if log_ptr.file_num == self.monotonic {
-                KvStore::value_at_pos(&self.log_file, log_ptr.pos)?
-            } else {
-                let log_file =
-                    KvStore::open_file(&self.path.join(format!("{}.log", log_ptr.file_num)))?;
-                KvStore::value_at_pos(&log_file, log_ptr.pos)?
-            };
-            let pos = new_log.seek(SeekFrom::End(0))?;
-            let writer = BufWriter::new(&new_log);
-            bincode::serialize_into(
-                writer,
-                &Op::Set {
-                    key: key.clone(),
-                    value,
-                },
-            )?;
-            log_ptr.file_num = self.monotonic + 1;
-            log_ptr.pos = pos;
+    /// List the keys currently in the store in sorted order, optionally only
+    /// those starting with `prefix`. Note that this materializes every
+    /// matching key in memory, which may be expensive for very large stores.
+    pub fn keys(&self, prefix: Option<&str>) -> Vec<String> {
+        match &self.prefix_index {
+            Some(prefix_index) => prefixed_keys(prefix_index, prefix.unwrap_or(""), None).collect(),
+            None => sorted_keys(&self.index, prefix),
         }
-        remove_file(self.path.join(format!("{}.log", self.monotonic)))?;
-        self.log_file = new_log;
-        self.compactions = 0;
-        self.monotonic += 1;
-        Ok(())
     }
 
-    fn sorted_file_nums(path: &PathBuf) -> KvsResult<Vec<u64>> {
-        let mut log_files: Vec<u64> = read_dir(path)?
-            .filter_map(|fp| {
-                if let Ok(fp) = fp {
-                    let file_name = fp.file_name().into_string();
-                    match (fp.path().is_dir(), file_name) {
-                        (true, _) => None,
-                        (false, Ok(n)) if n.ends_with(".log") => KvStore::parse_file_num(&n),
-                        _ => None,
-                    }
-                } else {
-                    None
-                }
-            })
-            .collect();
-        log_files.sort();
-        Ok(log_files)
+    /// Number of keys currently in the store starting with `prefix`.
+    pub fn count_prefix(&self, prefix: &str) -> usize {
+        match &self.prefix_index {
+            Some(prefix_index) => prefixed_keys(prefix_index, prefix, None).count(),
+            None => self
+                .index
+                .keys()
+                .filter(|key| key.starts_with(prefix))
+                .count(),
+        }
     }
 
-    fn parse_file_num(file_name: &str) -> Option<u64> {
-        file_name
-            .chars()
-            .take_while(|c| c.is_digit(10))
-            .fold("".to_owned(), |acc, c| format!("{}{}", acc, c))
-            .parse::<u64>()
-            .ok()
+    /// Get up to `limit` entries whose key starts with `prefix` in key order,
+    /// beginning after the key `cursor`. Pass the returned
+    /// [ScanPage::next_cursor] to get the next page. Keys set or removed
+    /// between pages are picked up or skipped depending on where they sort.
+    /// Entries skipped under [GetErrorPolicy::TreatCorruptAsMissing] still
+    /// count towards `limit`.
+    pub fn scan_page(
+        &mut self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> KvsResult<ScanPage> {
+        let mut keys: Vec<String> = match &self.prefix_index {
+            // Only one more than a page is needed to tell if there's another
+            Some(prefix_index) => prefixed_keys(prefix_index, prefix, cursor)
+                .take(limit.saturating_add(1))
+                .collect(),
+            None => sorted_keys(&self.index, Some(prefix))
+                .into_iter()
+                .filter(|key| cursor.is_none_or(|cursor| key.as_str() > cursor))
+                .collect(),
+        };
+        let more = keys.len() > limit;
+        keys.truncate(limit);
+        let next_cursor = if more { keys.last().cloned() } else { None };
+        let mut entries = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.get(key.clone())? {
+                entries.push((key, value));
+            }
+        }
+        Ok(ScanPage {
+            entries,
+            next_cursor,
+        })
     }
 
-    fn open_file(path: &PathBuf) -> Result<File, std::io::Error> {
-        OpenOptions::new()
-            .create(true)
-            .read(true)
-            // Always append the log
-            .append(true)
-            .open(path.join(path))
+    /// Physical location of the entry for `key` as the number of the log file
+    /// and the byte offset within it. Useful for debugging compaction and
+    /// corruption.
+    pub fn locate(&self, key: &str) -> Option<(u64, u64)> {
+        self.index
+            .get(key)
+            .map(|log_ptr| (log_ptr.file_num, log_ptr.pos))
     }
 
-    fn current_pos<S: Seek>(reader: &mut S) -> KvsResult<u64> {
-        Ok(reader.seek(SeekFrom::Current(0))?)
+    /// When the entry for `key` was last set. Entries written before log
+    /// entries recorded their time report the Unix epoch.
+    pub fn last_modified(&self, key: &str) -> Option<SystemTime> {
+        self.index
+            .get(key)
+            .map(|log_ptr| UNIX_EPOCH + Duration::from_millis(log_ptr.unix_ms))
     }
 
-    fn value_at_pos<S: Seek + std::io::Read>(mut reader: S, pos: u64) -> KvsResult<String> {
-        reader.seek(SeekFrom::Start(pos))?;
-        match bincode::deserialize_from(reader)? {
-            Op::Set { value, .. } => Ok(value),
-            // TODO: create error enum for this. If this happens the
-            // index is somewhat corrupted and should maybe be rebuilt.
-            Op::Rm { key } => Err(KvsError::KeyNotFound { key }),
+    /// Cheap check that the store can still be written to, i.e. its directory
+    /// and active log file still exist. Followers only check that the log can
+    /// be listed. Errors are reported in the result rather than returned.
+    pub fn health(&self) -> Health {
+        if let Some(dir) = self.log.storage.dir() {
+            if !dir.is_dir() {
+                return Health {
+                    ok: false,
+                    detail: format!("data directory {} is missing", dir.display()),
+                };
+            }
         }
-    }
+        let (ok, detail) = match self.log.storage.list_segments() {
+            Err(err) => (false, format!("log files can't be listed: {}", err)),
+            Ok(_) if self.log.active.is_none() => (true, "following".to_owned()),
+            Ok(file_nums) if file_nums.contains(&self.log.monotonic) => {
+                (true, format!("writing to log file {}", self.log.monotonic))
+            }
+            Ok(_) => (
+                false,
+                format!("active log file {} is missing", self.log.monotonic),
+            ),
+        };
+        Health { ok, detail }
+    }
+
+    /// Capture the entries currently in the store so several keys can be read
+    /// consistently while the store keeps being written to. Only the index is
+    /// copied; values are read from the log, which is append-only except for
+    /// compaction. Compaction leaves the log files the snapshot reads from in
+    /// place until it's dropped, so they keep taking up space in the meantime.
+    pub fn snapshot(&self) -> Snapshot<S> {
+        let pinned: BTreeSet<u64> = self
+            .index
+            .values()
+            .map(|log_ptr| log_ptr.file_num)
+            .collect();
+        let mut pins = self.log.pins.lock().unwrap();
+        for file_num in &pinned {
+            *pins.counts.entry(*file_num).or_default() += 1;
+        }
+        Snapshot {
+            log: Log {
+                storage: self.log.storage.clone(),
+                active: None,
+                monotonic: self.log.monotonic,
+                encoding: self.log.encoding,
+                formats: self.log.formats.clone(),
+                max_segment_bytes: self.log.max_segment_bytes,
+                intern_keys: false,
+                key_ids: KeyIds::default(),
+                pins: self.log.pins.clone(),
+            },
+            index: self.index.clone(),
+            pinned,
+        }
+    }
+
+    /// Every entry currently in the store in key order, for feeding an
+    /// external index or the like. Only the sorted keys are held in memory;
+    /// each value is read from a [Snapshot] as the iterator reaches it, so
+    /// writes and compactions in the meantime don't change what's returned.
+    pub fn full_scan_sorted(&self) -> SortedScan<S> {
+        let snapshot = self.snapshot();
+        let keys = snapshot.keys(None).into_iter();
+        SortedScan { snapshot, keys }
+    }
+
+    /// Read the entries of `keys` so the operating system caches them and
+    /// later reads of them are fast, e.g. right after a restart. Keys that
+    /// aren't in the store are ignored.
+    pub fn prewarm(&self, keys: &[String]) -> KvsResult<()> {
+        self.read_entries(keys.iter().filter_map(|key| self.index.get(key)))
+    }
+
+    /// Like [prewarm](KvStore::prewarm) for every key in the store.
+    pub fn prewarm_all(&self) -> KvsResult<()> {
+        self.read_entries(self.index.values())
+    }
+
+    /// Read the raw bytes of the entries `log_ptrs` point to in log order,
+    /// opening each log file once.
+    fn read_entries<'a>(&self, log_ptrs: impl Iterator<Item = &'a LogPtr>) -> KvsResult<()> {
+        let mut log_ptrs: Vec<_> = log_ptrs.collect();
+        log_ptrs.sort_unstable_by_key(|log_ptr| (log_ptr.file_num, log_ptr.pos));
+        let mut buf = Vec::new();
+        let mut segment = None;
+        for log_ptr in log_ptrs {
+            let segment = match &mut segment {
+                Some((file_num, segment)) if *file_num == log_ptr.file_num => segment,
+                _ => {
+                    let opened = self.log.storage.read_segment(log_ptr.file_num)?;
+                    &mut segment.insert((log_ptr.file_num, opened)).1
+                }
+            };
+            buf.resize(log_ptr.len as usize, 0);
+            segment.seek(SeekFrom::Start(log_ptr.pos))?;
+            segment.read_exact(&mut buf)?;
+        }
+        Ok(())
+    }
+
+    /// How much space the store takes up on disk and how much of it is live.
+    pub fn disk_usage(&self) -> KvsResult<DiskUsage> {
+        let file_nums = self.log.storage.list_segments()?;
+        let mut total_log_bytes = 0;
+        for file_num in &file_nums {
+            total_log_bytes += self
+                .log
+                .storage
+                .read_segment(*file_num)?
+                .seek(SeekFrom::End(0))?;
+        }
+        Ok(DiskUsage {
+            total_log_bytes,
+            live_value_bytes_estimate: self.index.values().map(|log_ptr| log_ptr.len).sum(),
+            num_segments: file_nums.len(),
+        })
+    }
+
+    /// Check that every key in the index points at a set of that key in the
+    /// log, collecting mismatches rather than stopping at the first one. An
+    /// error is only returned if the log can't be accessed at all.
+    pub fn verify(&mut self) -> KvsResult<VerifyReport> {
+        let mut report = VerifyReport::default();
+        // Keys interned in each log file, read as they're needed
+        let mut defined = HashMap::new();
+        for (key, log_ptr) in &self.index {
+            report.keys_checked += 1;
+            let reason = match self.log.read_op(log_ptr) {
+                Ok(Op::Set { key: found, .. }) if found == *key => continue,
+                Ok(Op::Set { key: found, .. }) => format!("entry is for key {}", found),
+                Ok(Op::SetById { key_id, .. }) => {
+                    let keys = match defined.entry(log_ptr.file_num) {
+                        Entry::Occupied(entry) => entry.into_mut(),
+                        Entry::Vacant(entry) => {
+                            entry.insert(self.log.defined_keys(log_ptr.file_num)?)
+                        }
+                    };
+                    match keys.get(&key_id) {
+                        Some(found) if found == key => continue,
+                        Some(found) => format!("entry is for key {}", found),
+                        None => format!("entry is for undefined key id {}", key_id),
+                    }
+                }
+                Ok(Op::Rm { .. }) => "entry is a removal".to_owned(),
+                Ok(Op::Batch { .. }) => "entry is the start of a batch".to_owned(),
+                Ok(Op::DefineKey { .. }) => "entry is a key definition".to_owned(),
+                Err(KvsError::Io { cause }) => return Err(KvsError::Io { cause }),
+                Err(err) => format!("entry can't be read: {}", err),
+            };
+            report.mismatches.push(IndexMismatch {
+                key: key.clone(),
+                file_num: log_ptr.file_num,
+                pos: log_ptr.pos,
+                reason,
+            });
+        }
+        report.mismatches.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(report)
+    }
+
+    /// The last `n` entries in the log, oldest first, along with the number of
+    /// the log file and the position of each. Only the newest log files are
+    /// read, as many as needed to find `n` entries. Compaction rewrites the
+    /// log in key order, so entries from before the last compaction aren't
+    /// in the order they were written.
+    pub fn recent_ops(&self, n: usize) -> KvsResult<Vec<(u64, u64, OpSummary)>> {
+        let mut recent = Vec::new();
+        for file_num in self.log.storage.list_segments()?.into_iter().rev() {
+            if recent.len() >= n {
+                break;
+            }
+            let mut segment = self.log.storage.read_segment(file_num)?;
+            let (format, start) = match format::read_header(&mut segment, file_num)? {
+                Some(header) => header,
+                None => continue,
+            };
+            // Keys interned in the log file so far
+            let mut keys: HashMap<u64, String> = HashMap::new();
+            let ops: Vec<_> = OpIter::new(segment, format, start)?
+                .filter_map(|(pos, op)| {
+                    let summary = match op {
+                        Op::Set { key, .. } => OpSummary {
+                            kind: OpKind::Set,
+                            key,
+                        },
+                        Op::SetById { key_id, .. } => OpSummary {
+                            kind: OpKind::Set,
+                            key: keys.get(&key_id)?.clone(),
+                        },
+                        Op::Rm { key, .. } => OpSummary {
+                            kind: OpKind::Remove,
+                            key,
+                        },
+                        // The entries of a batch are listed on their own
+                        Op::Batch { .. } => return None,
+                        Op::DefineKey { key_id, key } => {
+                            keys.insert(key_id, key);
+                            return None;
+                        }
+                    };
+                    Some((file_num, pos, summary))
+                })
+                .collect();
+            // Collected newest first
+            recent.extend(ops.into_iter().rev().take(n - recent.len()));
+        }
+        recent.reverse();
+        Ok(recent)
+    }
+
+    /// Register `callback` to be called with a [CompactionReport] after each
+    /// compaction, whether it was triggered automatically or manually.
+    /// Replaces any previously registered callback.
+    pub fn on_compaction(&mut self, callback: CompactionCallback) {
+        self.on_compaction = CompactionHook(Some(callback));
+    }
+
+    fn compact_maybe(&mut self) -> KvsResult<bool> {
+        let mostly_dead = self.dead_bytes >= MIN_COMPACTION_BYTES
+            && self.dead_bytes as f64 > self.compaction_ratio * self.total_bytes as f64;
+        let due = self.dead_bytes > 0
+            && self
+                .compaction_interval
+                .is_some_and(|interval| self.last_compaction.elapsed() >= interval);
+        if mostly_dead || due {
+            self.compact()?;
+        }
+        Ok(mostly_dead || due)
+    }
+
+    /// Forces compaction. Rewrites log, eliminating unnecessary logs, i.e.
+    /// removals and sets that are overwritten later. Entries are written in
+    /// key order, so compacting the same entries always produces the same
+    /// bytes.
+    pub fn compact(&mut self) -> KvsResult<()> {
+        self.check_writable()?;
+        self.rewrite().map_err(|err| self.log.dir_missing(err))
+    }
+
+    /// Replace everything in the store with `entries`, later entries for the
+    /// same key winning. The entries are written to a new log file along with
+    /// removals of the keys that aren't among them, which then takes the place
+    /// of the existing log files in a single step, so the old and new
+    /// contents are never mixed, even after a crash. Holds all of `entries`
+    /// in memory.
+    pub fn replace_all(
+        &mut self,
+        entries: impl IntoIterator<Item = (String, String)>,
+    ) -> KvsResult<()> {
+        self.check_writable()?;
+        let mut new_entries = BTreeMap::new();
+        for (key, value) in entries {
+            self.validate_key(&key)?;
+            new_entries.insert(key, value);
+        }
+        self.replace(new_entries)
+            .map_err(|err| self.log.dir_missing(err))
+    }
+
+    /// Write `new_entries` and removals of every other key into a new log
+    /// file and remove the old ones.
+    fn replace(&mut self, new_entries: BTreeMap<String, String>) -> KvsResult<()> {
+        let unix_ms = format::now_ms();
+        let mut removals: BTreeMap<String, u64> = self
+            .tombstones
+            .iter()
+            .map(|(key, unix_ms)| (key.clone(), *unix_ms))
+            .collect();
+        removals.extend(self.index.keys().map(|key| (key.clone(), unix_ms)));
+        removals.retain(|key, _| !new_entries.contains_key(key));
+        let ops: Vec<Op> = new_entries
+            .into_iter()
+            .map(|(key, value)| Op::Set {
+                key,
+                value,
+                unix_ms,
+                flags: 0,
+            })
+            .chain(
+                removals
+                    .into_iter()
+                    .map(|(key, unix_ms)| Op::Rm { key, unix_ms }),
+            )
+            .collect();
+        if let Some(max_total_bytes) = self.max_total_bytes {
+            if self.log.encoded_len(&ops)? > max_total_bytes {
+                return Err(KvsError::StorageFull { max_total_bytes });
+            }
+        }
+        let new_file_num = self.log.monotonic + 1;
+        let mut new_log = self.log.create_temp_segment(new_file_num)?;
+        let written = format::write_ops(&mut new_log, self.log.encoding, &ops)?;
+        let new_log = self.log.storage.commit_segment(new_file_num, new_log)?;
+        self.index.clear();
+        if let Some(prefix_index) = &mut self.prefix_index {
+            prefix_index.clear();
+        }
+        self.tombstones.clear();
+        self.total_bytes = 0;
+        self.dead_bytes = 0;
+        // Apply the entries the same way replaying the new log file would
+        for (op, (pos, len)) in ops.into_iter().zip(written) {
+            if let Op::Set { .. } = op {
+                self.count(|stats| stats.sets += 1);
+            }
+            self.apply(new_file_num, pos, len, op)?;
+        }
+        self.log.active = Some(new_log);
+        self.log.monotonic = new_file_num;
+        self.log.reset_key_ids(new_file_num);
+        self.log.pins.lock().unwrap().superseded_below = new_file_num;
+        self.log.remove_superseded()?;
+        Ok(())
+    }
+
+    /// Make every entry written so far durable and return where in the log
+    /// that is. A copy of the data directory taken after this returns opens
+    /// with every entry up to the checkpoint, although a copy taken while the
+    /// store is being written to may include later entries too, or even part
+    /// of one, which is ignored like after a crash.
+    pub fn checkpoint(&mut self) -> KvsResult<Checkpoint> {
+        self.check_writable()?;
+        let offset = self.log.active()?.seek(SeekFrom::End(0))?;
+        self.log
+            .storage
+            .sync_all()
+            .map_err(|err| self.log.dir_missing(err.into()))?;
+        Ok(Checkpoint {
+            file_num: self.log.monotonic,
+            offset,
+        })
+    }
+
+    /// Compact only if more than `min_reclaim_ratio` of the log is entries
+    /// that have been overwritten or removed, returning whether it did.
+    pub fn compact_if(&mut self, min_reclaim_ratio: f64) -> KvsResult<bool> {
+        self.check_writable()?;
+        if self.dead_bytes > 0
+            && self.dead_bytes as f64 > min_reclaim_ratio * self.total_bytes as f64
+        {
+            self.compact()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Recover from [KvsError::DataDirMissing] by recreating the directory and
+    /// rebuilding the index from whatever log files it now holds. Entries in
+    /// log files that were deleted are lost. Options and the compaction
+    /// callback are kept.
+    pub fn reopen(&mut self) -> KvsResult<()> {
+        if let Some(dir) = self.log.storage.dir() {
+            create_dir_all(dir)?;
+        }
+        let storage = self.log.storage.clone();
+        let mut store = if self.log.active.is_some() {
+            KvStore::open_with_storage(storage, self.options())?
+        } else {
+            KvStore::open_follower_with_storage(storage, self.options())?
+        };
+        std::mem::swap(&mut store.on_compaction, &mut self.on_compaction);
+        // Snapshots taken before still read from the log files they pinned
+        store.log.pins = self.log.pins.clone();
+        store.stats = self.stats;
+        store.saved_stats = self.saved_stats;
+        store.unsaved_ops = self.unsaved_ops;
+        // Keep `self` from saving its counts when it's dropped
+        self.saved_stats = self.stats;
+        self.unsaved_ops = 0;
+        *self = store;
+        Ok(())
+    }
+
+    /// The options this store was opened with.
+    fn options(&self) -> KvStoreOptions {
+        KvStoreOptions {
+            encoding: self.log.encoding,
+            tombstone_retention: self.tombstone_retention,
+            max_segment_bytes: self.log.max_segment_bytes,
+            compaction_segment_bytes: self.compaction_segment_bytes,
+            compaction_ratio: self.compaction_ratio,
+            compaction_interval: self.compaction_interval,
+            get_error_policy: self.get_error_policy,
+            max_key_len: self.max_key_len,
+            max_total_bytes: self.max_total_bytes,
+            enable_prefix_index: self.prefix_index.is_some(),
+            intern_keys: self.log.intern_keys,
+        }
+    }
+
+    /// Rewrite every live entry into a new log file and remove the old ones.
+    fn rewrite(&mut self) -> KvsResult<()> {
+        let first_file_num = self.log.monotonic + 1;
+        // Written under a temporary name so a crash part way through leaves
+        // the existing log files as they were
+        let mut new_log = Rewriter {
+            file_num: first_file_num,
+            segment: self.log.create_temp_segment(first_file_num)?,
+            has_entries: false,
+            max_segment_bytes: self.compaction_segment_bytes,
+            bytes: 0,
+        };
+        let mut new_index = HashMap::with_capacity(self.index.len());
+        let mut total_bytes = 0;
+        // Sorted so the same live entries always produce the same log file
+        let mut entries: Vec<_> = self.index.iter().collect();
+        entries.sort_unstable_by_key(|(key, _)| *key);
+        for (key, log_ptr) in entries {
+            // Even if we error out writing these, the data will not be
+            // corrupted
+            let (value, unix_ms, flags) = self.log.read_entry(log_ptr)?;
+            let (file_num, pos, len) = self.log.rewrite_op(
+                &mut new_log,
+                &Op::Set {
+                    key: key.clone(),
+                    value,
+                    unix_ms,
+                    flags,
+                },
+            )?;
+            new_index.insert(
+                key.clone(),
+                LogPtr {
+                    file_num,
+                    pos,
+                    len,
+                    unix_ms,
+                },
+            );
+            total_bytes += len;
+        }
+        let mut tombstones = HashMap::new();
+        if let Some(retention) = self.tombstone_retention {
+            let cutoff = format::now_ms().saturating_sub(retention.as_millis() as u64);
+            let mut removals: Vec<_> = self.tombstones.iter().collect();
+            removals.sort_unstable();
+            for (key, unix_ms) in removals {
+                if *unix_ms >= cutoff {
+                    let (_, _, len) = self.log.rewrite_op(
+                        &mut new_log,
+                        &Op::Rm {
+                            key: key.clone(),
+                            unix_ms: *unix_ms,
+                        },
+                    )?;
+                    total_bytes += len;
+                    tombstones.insert(key.clone(), *unix_ms);
+                }
+            }
+        }
+        let new_file_num = new_log.file_num;
+        let new_bytes = new_log.bytes + new_log.segment.seek(SeekFrom::End(0))?;
+        let new_log = self
+            .log
+            .storage
+            .commit_segment(new_file_num, new_log.segment)?;
+        // Every older log file has been superseded by the new ones
+        self.log.pins.lock().unwrap().superseded_below = first_file_num;
+        let removed = self.log.remove_superseded()?;
+        let old_bytes: u64 = removed.iter().map(|(_, len)| len).sum();
+        let report = CompactionReport {
+            old_file_nums: removed.into_iter().map(|(file_num, _)| file_num).collect(),
+            new_file_num,
+            keys_retained: new_index.len(),
+            bytes_reclaimed: old_bytes.saturating_sub(new_bytes),
+        };
+        self.index = new_index;
+        self.tombstones = tombstones;
+        self.log.active = Some(new_log);
+        self.log.monotonic = new_file_num;
+        self.log.reset_key_ids(new_file_num);
+        self.total_bytes = total_bytes;
+        self.dead_bytes = 0;
+        self.last_compaction = Instant::now();
+        if let Some(callback) = &self.on_compaction.0 {
+            callback(&report);
+        }
+        Ok(())
+    }
 }
 
+/// The segments of the write-ahead log. Kept separate from the index so a
+/// value can be read while iterating over the index.
 #[derive(Debug)]
+struct Log<S: LogStorage> {
+    storage: S,
+    /// Segment that new entries are appended to. Followers don't have one
+    active: Option<S::Segment>,
+    /// max id of current log files
+    monotonic: u64,
+    /// Encoding of new log files
+    encoding: LogEncoding,
+    /// Format of each non-empty log file
+    formats: HashMap<u64, SegmentFormat>,
+    /// Size at which the active segment is rotated
+    max_segment_bytes: Option<u64>,
+    /// Whether sets appended to the active segment refer to keys by id
+    intern_keys: bool,
+    /// Keys interned in the last log file replayed or appended to
+    key_ids: KeyIds,
+    /// Shared with the snapshots taken of the store
+    pins: Arc<Mutex<Pins>>,
+}
+
+/// Keys interned in log file `file_num`, see [Op::DefineKey].
+#[derive(Debug, Default)]
+struct KeyIds {
+    file_num: u64,
+    /// Key each id was defined as
+    keys: HashMap<u64, String>,
+    /// Id of each key, only kept when interning keys
+    ids: HashMap<String, u64>,
+}
+
+impl KeyIds {
+    /// Replace the sets among `ops` with sets by id, preceded by the
+    /// definitions of the keys that don't have an id yet. The definitions
+    /// come first so a batch stays contiguous.
+    fn intern(&self, ops: Vec<Op>) -> Vec<Op> {
+        let mut new_ids = HashMap::new();
+        let mut defs = Vec::new();
+        let mut interned = Vec::with_capacity(ops.len());
+        for op in ops {
+            interned.push(match op {
+                Op::Set {
+                    key,
+                    value,
+                    unix_ms,
+                    flags,
+                } => {
+                    let key_id = match self.ids.get(&key).or_else(|| new_ids.get(&key)) {
+                        Some(key_id) => *key_id,
+                        None => {
+                            let key_id = (self.keys.len() + defs.len()) as u64;
+                            new_ids.insert(key.clone(), key_id);
+                            defs.push(Op::DefineKey { key_id, key });
+                            key_id
+                        }
+                    };
+                    Op::SetById {
+                        key_id,
+                        value,
+                        unix_ms,
+                        flags,
+                    }
+                }
+                op => op,
+            });
+        }
+        defs.extend(interned);
+        defs
+    }
+}
+
+/// The temporary log file compaction is currently writing.
+struct Rewriter<S: LogStorage> {
+    file_num: u64,
+    segment: S::Segment,
+    /// Whether `segment` has any entries yet, so none is left with only a
+    /// header
+    has_entries: bool,
+    /// Size at which to start a new log file
+    max_segment_bytes: Option<u64>,
+    /// Combined size of the log files already committed
+    bytes: u64,
+}
+
+/// Which log files open snapshots still read from.
+#[derive(Debug, Default)]
+struct Pins {
+    /// Number of snapshots reading from each log file
+    counts: BTreeMap<u64, usize>,
+    /// Compaction superseded every log file below this one
+    superseded_below: u64,
+}
+
+impl<S: LogStorage> Log<S> {
+    /// Open a temporary log file with a header for writing the entries of log
+    /// file `file_num`. It must be committed once it's complete.
+    fn create_temp_segment(&mut self, file_num: u64) -> KvsResult<S::Segment> {
+        let mut segment = self.storage.create_temp_segment(file_num)?;
+        let format = format::init_segment(&mut segment, self.encoding)?;
+        self.formats.insert(file_num, format);
+        Ok(segment)
+    }
+
+    /// Combined size of `ops` once encoded.
+    fn encoded_len(&self, ops: &[Op]) -> KvsResult<u64> {
+        let mut len = 0;
+        for op in ops {
+            len += format::encoded_len(self.encoding, op)?;
+        }
+        Ok(len)
+    }
+
+    /// Combined size of the entries [Log::append_interned] would write for
+    /// `ops`.
+    fn appended_len(&mut self, ops: &[Op]) -> KvsResult<u64> {
+        if !self.intern_keys {
+            return self.encoded_len(ops);
+        }
+        // A new active segment doesn't have any keys interned yet
+        let fresh = KeyIds::default();
+        let key_ids = if self.is_full()? {
+            &fresh
+        } else {
+            &self.key_ids
+        };
+        let interned = key_ids.intern(ops.to_vec());
+        self.encoded_len(&interned)
+    }
+
+    /// Whether the active segment has reached the size limit.
+    fn is_full(&mut self) -> KvsResult<bool> {
+        Ok(match self.max_segment_bytes {
+            Some(max_segment_bytes) => self.active()?.seek(SeekFrom::End(0))? >= max_segment_bytes,
+            None => false,
+        })
+    }
+
+    /// Append `op` to the active segment, returning its position and length.
+    /// Starts a new active segment first if the current one is full.
+    fn append(&mut self, op: &Op) -> KvsResult<(u64, u64)> {
+        if self.is_full()? {
+            self.rotate()?;
+        }
+        let encoding = self.encoding;
+        let written = format::write_op(self.active()?, encoding, op)?;
+        self.check_dir()?;
+        Ok(written)
+    }
+
+    /// Append all `ops` to the active segment, interning the keys of sets
+    /// first if enabled. Returns the entries written along with the position
+    /// and length of each. They are always written to a single segment, so it
+    /// may grow past the size limit.
+    fn append_interned(&mut self, ops: Vec<Op>) -> KvsResult<Vec<(u64, u64, Op)>> {
+        if self.is_full()? {
+            self.rotate()?;
+        }
+        let ops = if self.intern_keys {
+            self.key_ids.intern(ops)
+        } else {
+            ops
+        };
+        let encoding = self.encoding;
+        let written = format::write_ops(self.active()?, encoding, &ops)?;
+        self.check_dir()?;
+        Ok(ops
+            .into_iter()
+            .zip(written)
+            .map(|(op, (pos, len))| (pos, len, op))
+            .collect())
+    }
+
+    /// Forget the keys interned so far, which only apply to the log file they
+    /// were defined in, to start on log file `file_num`.
+    fn reset_key_ids(&mut self, file_num: u64) {
+        self.key_ids = KeyIds {
+            file_num,
+            ..KeyIds::default()
+        };
+    }
+
+    /// Keys defined in log file `file_num`, see [Op::DefineKey].
+    fn defined_keys(&self, file_num: u64) -> KvsResult<HashMap<u64, String>> {
+        let mut segment = self.storage.read_segment(file_num)?;
+        let (format, start) = match format::read_header(&mut segment, file_num)? {
+            Some(header) => header,
+            None => return Ok(HashMap::new()),
+        };
+        Ok(OpIter::new(segment, format, start)?
+            .filter_map(|(_, op)| match op {
+                Op::DefineKey { key_id, key } => Some((key_id, key)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Replace a "not found" error caused by the directory of the log having
+    /// been deleted with [KvsError::DataDirMissing].
+    fn dir_missing(&self, err: KvsError) -> KvsError {
+        match (&err, self.storage.dir()) {
+            (KvsError::Io { cause }, Some(dir))
+                if cause.kind() == io::ErrorKind::NotFound && !dir.is_dir() =>
+            {
+                KvsError::DataDirMissing {
+                    path: dir.to_owned(),
+                }
+            }
+            _ => err,
+        }
+    }
+
+    /// Writes to the active segment still succeed once the data directory
+    /// has been deleted, only to be lost, so check that it's still there.
+    fn check_dir(&self) -> KvsResult<()> {
+        match self.storage.dir() {
+            Some(dir) if !dir.is_dir() => Err(KvsError::DataDirMissing {
+                path: dir.to_owned(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// The segment to append to, if this isn't a follower.
+    fn active(&mut self) -> KvsResult<&mut S::Segment> {
+        self.active.as_mut().ok_or(KvsError::ReadOnly)
+    }
+
+    /// Make a new, empty segment the active one.
+    fn rotate(&mut self) -> KvsResult<()> {
+        let file_num = self.monotonic + 1;
+        let mut segment = self
+            .storage
+            .open_segment(file_num)
+            .map_err(|err| self.dir_missing(err.into()))?;
+        let format = format::init_segment(&mut segment, self.encoding)?;
+        self.formats.insert(file_num, format);
+        self.active = Some(segment);
+        self.monotonic = file_num;
+        self.reset_key_ids(file_num);
+        Ok(())
+    }
+
+    /// Write `op` to the log files being written by compaction, committing
+    /// the current one first if it's full. Returns the number of the log file
+    /// it was written to along with its position and length. Log files are
+    /// committed in order, so a crash part way through leaves a prefix of
+    /// the new log files next to all of the old ones, which replays to the
+    /// same entries.
+    fn rewrite_op(&mut self, out: &mut Rewriter<S>, op: &Op) -> KvsResult<(u64, u64, u64)> {
+        if let Some(max_segment_bytes) = out.max_segment_bytes {
+            let bytes = out.segment.seek(SeekFrom::End(0))?;
+            if out.has_entries && bytes >= max_segment_bytes {
+                let next = self.create_temp_segment(out.file_num + 1)?;
+                let full = std::mem::replace(&mut out.segment, next);
+                self.storage.commit_segment(out.file_num, full)?;
+                out.file_num += 1;
+                out.has_entries = false;
+                out.bytes += bytes;
+            }
+        }
+        let (pos, len) = format::write_op(&mut out.segment, self.encoding, op)?;
+        out.has_entries = true;
+        Ok((out.file_num, pos, len))
+    }
+
+    /// Remove the log files superseded by compaction in ascending order,
+    /// stopping at the first one a snapshot still reads from. That way the
+    /// ones left behind still replay to the current entries, even when some
+    /// of their removals are no longer retained. Returns the number and size
+    /// of each removed log file.
+    fn remove_superseded(&mut self) -> KvsResult<Vec<(u64, u64)>> {
+        let pins = self.pins.lock().unwrap();
+        let mut removed = Vec::new();
+        for file_num in self.storage.list_segments()? {
+            if file_num >= pins.superseded_below || pins.counts.contains_key(&file_num) {
+                break;
+            }
+            let len = self
+                .storage
+                .read_segment(file_num)?
+                .seek(SeekFrom::End(0))?;
+            self.storage.remove_segment(file_num)?;
+            self.formats.remove(&file_num);
+            removed.push((file_num, len));
+        }
+        Ok(removed)
+    }
+
+    /// Read the value `log_ptr` points to, which may live in an older segment
+    /// than the active one.
+    fn read_value(&mut self, log_ptr: &LogPtr) -> KvsResult<String> {
+        self.read_entry(log_ptr).map(|(value, _, _)| value)
+    }
+
+    /// Read the entry `log_ptr` points to, whatever it is.
+    fn read_op(&mut self, log_ptr: &LogPtr) -> KvsResult<Op> {
+        self.read_segment(log_ptr.file_num, |segment, format| {
+            format::read_op(segment, format, log_ptr.pos)
+        })
+    }
+
+    /// Read part of the value of `key`, which `log_ptr` points to. See
+    /// [format::read_value_range].
+    fn read_value_range(
+        &mut self,
+        key: &str,
+        log_ptr: &LogPtr,
+        offset: u64,
+        len: u64,
+    ) -> KvsResult<Vec<u8>> {
+        let range = self.read_segment(log_ptr.file_num, |segment, format| {
+            format::read_value_range(segment, format, log_ptr.pos, offset, len)
+        })?;
+        range.ok_or_else(|| KvsError::IndexInconsistent {
+            key: key.to_owned(),
+            file_num: log_ptr.file_num,
+            pos: log_ptr.pos,
+        })
+    }
+
+    /// Call `read` with segment `file_num` and its format, reusing the active
+    /// segment's handle.
+    fn read_segment<T>(
+        &mut self,
+        file_num: u64,
+        read: impl FnOnce(&mut S::Segment, SegmentFormat) -> KvsResult<T>,
+    ) -> KvsResult<T> {
+        let format = self.formats[&file_num];
+        match &mut self.active {
+            Some(active) if file_num == self.monotonic => read(active, format),
+            _ => read(&mut self.storage.read_segment(file_num)?, format),
+        }
+    }
+
+    /// Read the value `log_ptr` points to along with when it was written and
+    /// its flags.
+    fn read_entry(&mut self, log_ptr: &LogPtr) -> KvsResult<(String, u64, u32)> {
+        match self.read_op(log_ptr)? {
+            Op::Set {
+                value,
+                unix_ms,
+                flags,
+                ..
+            }
+            | Op::SetById {
+                value,
+                unix_ms,
+                flags,
+                ..
+            } => Ok((value, unix_ms, flags)),
+            // The index only points at sets, so it's out of sync with the log
+            // and should be rebuilt
+            Op::Rm { key, .. } => Err(KvsError::IndexInconsistent {
+                key,
+                file_num: log_ptr.file_num,
+                pos: log_ptr.pos,
+            }),
+            Op::Batch { .. } | Op::DefineKey { .. } => Err(KvsError::Corruption {
+                reason: format!(
+                    "the index points to an entry that isn't a set in log file {} at {}",
+                    log_ptr.file_num, log_ptr.pos
+                ),
+            }),
+        }
+    }
+}
+
+/// Keys of `index` starting with `prefix`, if any, in sorted order.
+fn sorted_keys(index: &HashMap<String, LogPtr>, prefix: Option<&str>) -> Vec<String> {
+    let mut keys: Vec<String> = index
+        .keys()
+        .filter(|key| prefix.is_none_or(|prefix| key.starts_with(prefix)))
+        .cloned()
+        .collect();
+    keys.sort();
+    keys
+}
+
+/// Keys of the sorted `prefix_index` starting with `prefix`, beginning after
+/// `after` if given, without visiting any others.
+fn prefixed_keys<'a>(
+    prefix_index: &'a BTreeSet<String>,
+    prefix: &'a str,
+    after: Option<&str>,
+) -> impl Iterator<Item = String> + 'a {
+    let start = match after {
+        Some(after) if after >= prefix => Bound::Excluded(after),
+        _ => Bound::Included(prefix),
+    };
+    prefix_index
+        .range::<str, _>((start, Bound::Unbounded))
+        .take_while(move |key| key.starts_with(prefix))
+        .cloned()
+}
+
+#[derive(Clone, Debug)]
 struct LogPtr {
     pub file_num: u64,
     pub pos: u64,
-}
-
-#[derive(Deserialize, Serialize, Debug)]
-enum Op {
-    Set { key: String, value: String },
-    Rm { key: String },
+    /// Size of the entry, which becomes dead once it's superseded
+    pub len: u64,
+    /// When the entry was written, so it can be queried without a read
+    pub unix_ms: u64,
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::storage::MemStorage;
+
+    fn set_n(store: &mut KvStore<MemStorage>, n: usize, value: &str) -> KvsResult<()> {
+        for key_id in 0..n {
+            store.set(format!("key{}", key_id), value.to_owned())?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn recover_from_storage() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let mut store = KvStore::open_with_storage(storage.clone(), Default::default())?;
+        set_n(&mut store, 10, "value")?;
+        store.remove("key3".to_owned())?;
+        drop(store);
+
+        let mut store = KvStore::open_with_storage(storage, Default::default())?;
+        assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+        assert_eq!(store.keys(None).len(), 9);
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_replaces_segment() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let mut store = KvStore::open_with_storage(storage.clone(), Default::default())?;
+        set_n(&mut store, 10, "old")?;
+        // Stays below the compaction limit
+        set_n(&mut store, 10, "new")?;
+        assert_eq!(storage.list_segments()?, vec![1]);
+
+        store.compact()?;
+        assert_eq!(storage.list_segments()?, vec![2]);
+        assert_eq!(store.get("key9".to_owned())?, Some("new".to_owned()));
+        drop(store);
+
+        let mut store = KvStore::open_with_storage(storage, Default::default())?;
+        assert_eq!(store.get("key9".to_owned())?, Some("new".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn automatic_compaction() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let mut store = KvStore::open_with_storage(storage.clone(), Default::default())?;
+        let op = Op::Set {
+            key: "key0".to_owned(),
+            value: "value".to_owned(),
+            unix_ms: 0,
+            flags: 0,
+        };
+        // All but the last write are dead
+        let writes = MIN_COMPACTION_BYTES.div_ceil(bincode::serialized_size(&op)?) + 1;
+        for _ in 1..writes {
+            set_n(&mut store, 1, "value")?;
+        }
+        assert_eq!(storage.list_segments()?, vec![1]);
+        set_n(&mut store, 1, "value")?;
+        assert_eq!(storage.list_segments()?, vec![2]);
+        // Only a single record remains
+        let (_, start) = format::read_header(&mut storage.open_segment(2)?, 2)?.unwrap();
+        assert_eq!(
+            storage.segment_bytes(2).map(|bytes| bytes.len() as u64),
+            Some(start + bincode::serialized_size(&op)?)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_waits_for_dead_ratio() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let mut store = KvStore::open_with_storage(storage.clone(), Default::default())?;
+        // Unique keys leave nothing to reclaim
+        set_n(&mut store, 500, "value")?;
+        assert_eq!(store.dead_bytes, 0);
+        // Well above the minimum but less than half of the log
+        set_n(&mut store, 200, "value")?;
+        assert!(store.dead_bytes >= MIN_COMPACTION_BYTES);
+        assert_eq!(storage.list_segments()?, vec![1]);
+        drop(store);
+
+        let options = KvStoreOptions {
+            compaction_ratio: 0.25,
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_storage(storage.clone(), options)?;
+        set_n(&mut store, 1, "value")?;
+        assert_eq!(storage.list_segments()?, vec![2]);
+        assert_eq!(store.dead_bytes, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn index_pointing_at_removal() -> KvsResult<()> {
+        let mut store = KvStore::open_with_storage(MemStorage::default(), Default::default())?;
+        set_n(&mut store, 1, "value")?;
+        let (pos, _) = store.log.append(&Op::Rm {
+            key: "key0".to_owned(),
+            unix_ms: format::now_ms(),
+        })?;
+        store.index.get_mut("key0").unwrap().pos = pos;
+
+        match store.get("key0".to_owned()) {
+            Err(KvsError::IndexInconsistent {
+                key,
+                file_num,
+                pos: err_pos,
+            }) => {
+                assert_eq!(key, "key0");
+                assert_eq!(file_num, 1);
+                assert_eq!(err_pos, pos);
+            }
+            res => panic!("Unexpected result {:?}", res),
+        }
+        Ok(())
+    }
+
+    fn retaining(retention: Duration) -> KvStoreOptions {
+        KvStoreOptions {
+            tombstone_retention: Some(retention),
+            ..Default::default()
+        }
+    }
+
+    fn removals_in(storage: &MemStorage, file_num: u64) -> KvsResult<Vec<(String, u64)>> {
+        let mut segment = storage.open_segment(file_num)?;
+        let (format, start) = format::read_header(&mut segment, file_num)?.unwrap();
+        Ok(OpIter::new(segment, format, start)?
+            .filter_map(|(_, op)| match op {
+                Op::Rm { key, unix_ms } => Some((key, unix_ms)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    #[test]
+    fn compaction_drops_tombstones_by_default() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let mut store = KvStore::open_with_storage(storage.clone(), Default::default())?;
+        set_n(&mut store, 2, "value")?;
+        store.remove("key0".to_owned())?;
+        store.compact()?;
+        assert!(removals_in(&storage, 2)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_retains_recent_tombstones() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let options = retaining(Duration::from_secs(3600));
+        let mut store = KvStore::open_with_storage(storage.clone(), options.clone())?;
+        set_n(&mut store, 2, "value")?;
+        store.remove("key0".to_owned())?;
+        // Written long before the retention window
+        store.log.append(&Op::Rm {
+            key: "ancient".to_owned(),
+            unix_ms: 1,
+        })?;
+        drop(store);
+
+        let mut store = KvStore::open_with_storage(storage.clone(), options.clone())?;
+        store.compact()?;
+        let removals = removals_in(&storage, 2)?;
+        assert_eq!(removals.len(), 1);
+        assert_eq!(removals[0].0, "key0");
+        assert!(removals[0].1 > 1);
+        assert_eq!(store.get("key0".to_owned())?, None);
+
+        // Setting the key again supersedes the removal
+        store.set("key0".to_owned(), "again".to_owned())?;
+        store.compact()?;
+        assert!(removals_in(&storage, 3)?.is_empty());
+        drop(store);
+
+        let mut store = KvStore::open_with_storage(storage, options)?;
+        assert_eq!(store.get("key0".to_owned())?, Some("again".to_owned()));
+        assert_eq!(store.dead_bytes, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_callback() -> KvsResult<()> {
+        use std::sync::{Arc, Mutex};
+
+        let storage = MemStorage::default();
+        let mut store = KvStore::open_with_storage(storage.clone(), Default::default())?;
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let sink = reports.clone();
+        store.on_compaction(Box::new(move |report| {
+            sink.lock().unwrap().push(report.clone())
+        }));
+        set_n(&mut store, 10, "old")?;
+        set_n(&mut store, 10, "new")?;
+        let old_bytes = storage.segment_bytes(1).unwrap().len() as u64;
+        store.compact()?;
+
+        let new_bytes = storage.segment_bytes(2).unwrap().len() as u64;
+        assert_eq!(
+            *reports.lock().unwrap(),
+            vec![CompactionReport {
+                old_file_nums: vec![1],
+                new_file_num: 2,
+                keys_retained: 10,
+                bytes_reclaimed: old_bytes - new_bytes,
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rotate_full_segments() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let options = KvStoreOptions {
+            max_segment_bytes: Some(100),
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_storage(storage.clone(), options.clone())?;
+        set_n(&mut store, 20, "value")?;
+        let segments = storage.list_segments()?;
+        assert!(segments.len() > 1);
+        // Only the active segment may be below the limit
+        for file_num in &segments[..segments.len() - 1] {
+            assert!(storage.segment_bytes(*file_num).unwrap().len() >= 100);
+        }
+        drop(store);
+
+        let mut store = KvStore::open_with_storage(storage.clone(), options)?;
+        for key_id in 0..20 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some("value".to_owned())
+            );
+        }
+        store.compact()?;
+        assert_eq!(storage.list_segments()?, vec![segments.last().unwrap() + 1]);
+        assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn verify_index() -> KvsResult<()> {
+        let mut store = KvStore::open_with_storage(MemStorage::default(), Default::default())?;
+        set_n(&mut store, 4, "value")?;
+        assert_eq!(
+            store.verify()?,
+            VerifyReport {
+                keys_checked: 4,
+                mismatches: Vec::new(),
+            }
+        );
+
+        let (rm_pos, _) = store.log.append(&Op::Rm {
+            key: "key0".to_owned(),
+            unix_ms: format::now_ms(),
+        })?;
+        store.index.get_mut("key0").unwrap().pos = rm_pos;
+        let key1_pos = store.index["key1"].pos;
+        store.index.get_mut("key2").unwrap().pos = key1_pos;
+        store.index.get_mut("key3").unwrap().pos += 1;
+
+        let report = store.verify()?;
+        assert!(!report.is_ok());
+        assert_eq!(report.keys_checked, 4);
+        let mismatched: Vec<_> = report
+            .mismatches
+            .iter()
+            .map(|mismatch| (mismatch.key.as_str(), mismatch.reason.as_str()))
+            .collect();
+        assert_eq!(mismatched[0], ("key0", "entry is a removal"));
+        assert_eq!(mismatched[1], ("key2", "entry is for key key1"));
+        assert_eq!(mismatched[2].0, "key3");
+        assert_eq!(mismatched.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn get_corrupt_entry() -> KvsResult<()> {
+        use std::io::Write;
+
+        let storage = MemStorage::default();
+        let mut store = KvStore::open_with_storage(storage.clone(), Default::default())?;
+        set_n(&mut store, 2, "value")?;
+        // Not a valid variant of `Op`
+        let active = store.log.active()?;
+        let pos = active.seek(SeekFrom::End(0))?;
+        active.write_all(&[0xff; 8])?;
+        store.index.get_mut("key0").unwrap().pos = pos;
+        assert!(matches!(
+            store.get("key0".to_owned()),
+            Err(KvsError::Serialization { .. })
+        ));
+
+        store.get_error_policy = GetErrorPolicy::TreatCorruptAsMissing;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        assert_eq!(store.get("key1".to_owned())?, Some("value".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_set_invalid_key() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let options = KvStoreOptions {
+            max_key_len: Some(8),
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_storage(storage.clone(), options)?;
+        let entries = (0..2000).map(|key_id| match key_id {
+            1500 => ("key123456".to_owned(), "bad".to_owned()),
+            _ => (format!("key{}", key_id), "value".to_owned()),
+        });
+        match store.bulk_set(entries) {
+            Err(KvsError::InvalidKey { .. }) => {}
+            res => panic!("Unexpected result {:?}", res),
+        }
+
+        // The first chunk was written before the invalid key was reached
+        assert_eq!(store.keys(None).len(), BULK_CHUNK_LEN);
+        drop(store);
+        let store = KvStore::open_with_storage(storage, Default::default())?;
+        assert_eq!(store.keys(None).len(), BULK_CHUNK_LEN);
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_set_matches_set() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let mut store = KvStore::open_with_storage(storage.clone(), Default::default())?;
+        set_n(&mut store, 10, "old")?;
+        let entries = (0..2000).map(|key_id| (format!("key{}", key_id), "new".to_owned()));
+        store.bulk_set(entries)?;
+        assert_eq!(store.keys(None).len(), 2000);
+        assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+        assert_eq!(store.get("key1999".to_owned())?, Some("new".to_owned()));
+        let (total_bytes, dead_bytes) = (store.total_bytes, store.dead_bytes);
+        assert!(store.verify()?.is_ok());
+        drop(store);
+
+        let mut store = KvStore::open_with_storage(storage, Default::default())?;
+        assert_eq!(store.get("key1000".to_owned())?, Some("new".to_owned()));
+        assert_eq!(
+            (store.total_bytes, store.dead_bytes),
+            (total_bytes, dead_bytes)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn validate_keys() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let mut store = KvStore::open_with_storage(storage.clone(), Default::default())?;
+        // Permissive by default
+        store.set("".to_owned(), "value".to_owned())?;
+        drop(store);
+
+        let options = KvStoreOptions {
+            max_key_len: Some(8),
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_storage(storage, options)?;
+        let invalid = |res: KvsResult<()>| match res {
+            Err(KvsError::InvalidKey { reason }) => reason,
+            res => panic!("Unexpected result {:?}", res),
+        };
+        assert_eq!(
+            invalid(store.set("".to_owned(), "value".to_owned())),
+            "key is empty"
+        );
+        assert_eq!(
+            invalid(store.set("key123456".to_owned(), "value".to_owned())),
+            "key is 9 bytes, more than 8"
+        );
+        assert_eq!(
+            invalid(store.set("key\0".to_owned(), "value".to_owned())),
+            "key contains a control character"
+        );
+        invalid(store.remove("".to_owned()));
+        invalid(store.rename("key1".to_owned(), "".to_owned()));
+        invalid(store.bulk_set(vec![("".to_owned(), "value".to_owned())]));
+        store.set("key1".to_owned(), "value".to_owned())?;
+        assert_eq!(store.keys(None), vec!["", "key1"]);
+        Ok(())
+    }
+
+    #[test]
+    fn follower_tails_log() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let mut store = KvStore::open_with_storage(storage.clone(), Default::default())?;
+        set_n(&mut store, 3, "old")?;
+        let mut follower =
+            KvStore::open_follower_with_storage(storage.clone(), Default::default())?;
+        assert_eq!(follower.get("key2".to_owned())?, Some("old".to_owned()));
+
+        set_n(&mut store, 2, "new")?;
+        store.remove("key2".to_owned())?;
+        assert_eq!(follower.get("key0".to_owned())?, Some("old".to_owned()));
+        follower.refresh()?;
+        assert_eq!(follower.get("key0".to_owned())?, Some("new".to_owned()));
+        assert_eq!(follower.keys(None), vec!["key0", "key1"]);
+
+        store.compact()?;
+        store.set("key3".to_owned(), "value".to_owned())?;
+        follower.refresh()?;
+        assert_eq!(follower.keys(None), store.keys(None));
+        assert_eq!(follower.get("key1".to_owned())?, Some("new".to_owned()));
+        assert_eq!(
+            (follower.total_bytes, follower.dead_bytes),
+            (store.total_bytes, store.dead_bytes)
+        );
+
+        assert!(matches!(
+            follower.set("key0".to_owned(), "value".to_owned()),
+            Err(KvsError::ReadOnly)
+        ));
+        assert!(matches!(
+            follower.remove("key0".to_owned()),
+            Err(KvsError::ReadOnly)
+        ));
+        assert!(matches!(follower.compact(), Err(KvsError::ReadOnly)));
+        assert_eq!(storage.list_segments()?, vec![2]);
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_interval() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let options = KvStoreOptions {
+            compaction_interval: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_storage(storage.clone(), options)?;
+        set_n(&mut store, 2, "old")?;
+        set_n(&mut store, 1, "new")?;
+        assert_eq!(storage.list_segments()?, vec![1]);
+
+        // Pretend the interval has passed
+        store.compaction_interval = Some(Duration::from_secs(0));
+        set_n(&mut store, 1, "old")?;
+        assert_eq!(storage.list_segments()?, vec![2]);
+        // Nothing more to eliminate
+        store.set("key2".to_owned(), "value".to_owned())?;
+        assert_eq!(storage.list_segments()?, vec![2]);
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_is_deterministic() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let mut store =
+            KvStore::open_with_storage(storage.clone(), retaining(Duration::from_secs(3600)))?;
+        set_n(&mut store, 100, "value")?;
+        for key_id in 0..10 {
+            store.remove(format!("key{}", key_id))?;
+        }
+        store.compact()?;
+        let first = storage.segment_bytes(2).unwrap();
+        store.compact()?;
+        assert_eq!(storage.segment_bytes(3).unwrap(), first);
+        Ok(())
+    }
+
+    #[test]
+    fn health_check() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let store = KvStore::open_with_storage(storage.clone(), Default::default())?;
+        assert_eq!(
+            store.health(),
+            Health {
+                ok: true,
+                detail: "writing to log file 1".to_owned(),
+            }
+        );
+
+        storage.remove_segment(1)?;
+        assert_eq!(
+            store.health(),
+            Health {
+                ok: false,
+                detail: "active log file 1 is missing".to_owned(),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn get_range() -> KvsResult<()> {
+        for &encoding in &[LogEncoding::Bincode, LogEncoding::Json] {
+            let options = KvStoreOptions {
+                encoding,
+                ..Default::default()
+            };
+            let mut store = KvStore::open_with_storage(MemStorage::default(), options)?;
+            store.set("key1".to_owned(), "héllo world".to_owned())?;
+            let range = |store: &mut KvStore<_>, offset, len| {
+                store.get_range("key1".to_owned(), offset, len)
+            };
+            assert_eq!(range(&mut store, 7, 5)?, Some("world".to_owned()));
+            assert_eq!(range(&mut store, 0, 100)?, Some("héllo world".to_owned()));
+            assert_eq!(range(&mut store, 100, 5)?, Some(String::new()));
+            assert!(matches!(
+                range(&mut store, 2, 1),
+                Err(KvsError::InvalidRange { .. })
+            ));
+            assert_eq!(store.get_range("key2".to_owned(), 0, 5)?, None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn scan_pages() -> KvsResult<()> {
+        let mut store = KvStore::open_with_storage(MemStorage::default(), Default::default())?;
+        set_n(&mut store, 5, "value")?;
+        store.set("other".to_owned(), "value".to_owned())?;
+
+        let mut cursor = None;
+        let mut pages = Vec::new();
+        loop {
+            let page = store.scan_page("key", cursor.as_deref(), 2)?;
+            pages.push(
+                page.entries
+                    .iter()
+                    .map(|(key, _)| key.clone())
+                    .collect::<Vec<_>>(),
+            );
+            match page.next_cursor {
+                Some(next_cursor) => cursor = Some(next_cursor),
+                None => break,
+            }
+        }
+        assert_eq!(
+            pages,
+            vec![vec!["key0", "key1"], vec!["key2", "key3"], vec!["key4"]]
+        );
+        assert_eq!(
+            store.scan_page("", Some("key4"), 10)?,
+            ScanPage {
+                entries: vec![("other".to_owned(), "value".to_owned())],
+                next_cursor: None,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn prefix_index_matches_full_scan() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let options = KvStoreOptions {
+            enable_prefix_index: true,
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_storage(storage.clone(), options.clone())?;
+        set_n(&mut store, 5, "value")?;
+        for key in &["ke", "kez", "other", "key3"] {
+            store.set(key.to_string(), "value".to_owned())?;
+        }
+        store.remove("key1".to_owned())?;
+        store.compact()?;
+        drop(store);
+
+        let mut indexed = KvStore::open_with_storage(storage.clone(), options)?;
+        let mut unindexed = KvStore::open_with_storage(storage, Default::default())?;
+        for prefix in &["key", "ke", "k", "", "z"] {
+            assert_eq!(
+                indexed.keys(Some(prefix)),
+                unindexed.keys(Some(prefix)),
+                "{}",
+                prefix
+            );
+            assert_eq!(indexed.count_prefix(prefix), unindexed.count_prefix(prefix));
+            for cursor in &[None, Some("a"), Some("key0"), Some("key9")] {
+                assert_eq!(
+                    indexed.scan_page(prefix, *cursor, 2)?,
+                    unindexed.scan_page(prefix, *cursor, 2)?
+                );
+            }
+        }
+        assert_eq!(indexed.count_prefix("key"), 4);
+        assert_eq!(indexed.keys(None), unindexed.keys(None));
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_reads() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let mut store = KvStore::open_with_storage(storage, Default::default())?;
+        store.set("key1".to_owned(), "old".to_owned())?;
+        store.set("key2".to_owned(), "old".to_owned())?;
+        let mut snapshot = store.snapshot();
+        store.set("key1".to_owned(), "new".to_owned())?;
+        store.remove("key2".to_owned())?;
+        store.set("key3".to_owned(), "new".to_owned())?;
+
+        assert_eq!(snapshot.get("key1")?, Some("old".to_owned()));
+        assert_eq!(snapshot.get("key2")?, Some("old".to_owned()));
+        assert_eq!(snapshot.get("key3")?, None);
+        assert_eq!(snapshot.keys(None), vec!["key1", "key2"]);
+        assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn full_scan_is_sorted_and_consistent() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let mut store = KvStore::open_with_storage(storage.clone(), Default::default())?;
+        for key in &["b", "c", "a"] {
+            store.set(key.to_string(), "old".to_owned())?;
+        }
+        let mut scan = store.full_scan_sorted();
+        assert_eq!(
+            scan.next().transpose()?,
+            Some(("a".to_owned(), "old".to_owned()))
+        );
+        store.set("b".to_owned(), "new".to_owned())?;
+        store.remove("c".to_owned())?;
+        store.set("d".to_owned(), "new".to_owned())?;
+        store.compact()?;
+        assert_eq!(
+            scan.collect::<KvsResult<Vec<_>>>()?,
+            vec![
+                ("b".to_owned(), "old".to_owned()),
+                ("c".to_owned(), "old".to_owned())
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_pins_log_files() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let options = KvStoreOptions {
+            max_segment_bytes: Some(1),
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_storage(storage.clone(), options)?;
+        store.set("key1".to_owned(), "old".to_owned())?;
+        store.set("key2".to_owned(), "old".to_owned())?;
+        store.remove("key1".to_owned())?;
+        let (pinned, _) = store.locate("key2").unwrap();
+        let mut snapshot = store.snapshot();
+        store.set("key2".to_owned(), "new".to_owned())?;
+        store.compact()?;
+
+        // Only the log file before the pinned one is removed, later ones have
+        // to stay so the removal of key1 isn't lost
+        let new_file_num = *storage.list_segments()?.last().unwrap();
+        assert_eq!(
+            storage.list_segments()?,
+            (pinned..=new_file_num).collect::<Vec<_>>()
+        );
+        assert_eq!(snapshot.get("key1")?, None);
+        assert_eq!(snapshot.get("key2")?, Some("old".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("new".to_owned()));
+        let reopened = KvStore::open_with_storage(storage.clone(), Default::default())?;
+        assert_eq!(reopened.keys(None), vec!["key2"]);
+
+        drop(snapshot);
+        assert_eq!(storage.list_segments()?, vec![new_file_num]);
+
+        // Reopening keeps the files pinned by snapshots from before
+        let mut snapshot = store.snapshot();
+        store.set("key2".to_owned(), "newer".to_owned())?;
+        store.reopen()?;
+        store.compact()?;
+        assert_eq!(snapshot.get("key2")?, Some("new".to_owned()));
+        drop(snapshot);
+        assert_eq!(storage.list_segments()?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn replace_all_entries() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let mut store = KvStore::open_with_storage(storage.clone(), Default::default())?;
+        set_n(&mut store, 3, "old")?;
+        let mut snapshot = store.snapshot();
+        store.replace_all(vec![
+            ("key1".to_owned(), "first".to_owned()),
+            ("new".to_owned(), "value".to_owned()),
+            ("key1".to_owned(), "new".to_owned()),
+        ])?;
+        assert_eq!(store.keys(None), vec!["key1", "new"]);
+        assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+        assert_eq!(snapshot.get("key0")?, Some("old".to_owned()));
+        assert_eq!(store.stats().sets, 5);
+
+        // Even with the old log file still around, the removals keep the old
+        // keys from coming back
+        assert_eq!(storage.list_segments()?, vec![1, 2]);
+        let reopened = KvStore::open_with_storage(storage.clone(), Default::default())?;
+        assert_eq!(reopened.keys(None), store.keys(None));
+
+        drop(snapshot);
+        assert_eq!(storage.list_segments()?, vec![2]);
+        let reopened = KvStore::open_with_storage(storage, Default::default())?;
+        assert_eq!(reopened.keys(None), store.keys(None));
+        assert_eq!(
+            (reopened.total_bytes, reopened.dead_bytes),
+            (store.total_bytes, store.dead_bytes)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn missing_log_files() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let options = KvStoreOptions {
+            max_segment_bytes: Some(1),
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_storage(storage.clone(), options)?;
+        set_n(&mut store, 4, "value")?;
+        drop(store);
+        assert!(storage.list_segments()?.len() > 3);
+
+        storage.remove_segment(2)?;
+        storage.remove_segment(3)?;
+        match KvStore::open_with_storage(storage.clone(), Default::default()) {
+            Err(KvsError::Corruption { reason }) => {
+                assert_eq!(reason, "log files 2 to 3 are missing")
+            }
+            res => panic!("Unexpected result {:?}", res.map(|_| ())),
+        }
+        // Missing from the front is what compaction leaves behind
+        storage.remove_segment(1)?;
+        KvStore::open_with_storage(storage, Default::default())?;
+        Ok(())
+    }
+
+    #[test]
+    fn storage_full() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let options = KvStoreOptions {
+            max_total_bytes: Some(300),
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_storage(storage, options)?;
+        // Overwriting compacts once the limit is reached
+        for _ in 0..100 {
+            store.set("key".to_owned(), "value".to_owned())?;
+        }
+        assert!(store.total_bytes <= 300);
+        let mut key_id = 0;
+        let err = loop {
+            match store.set(format!("key{}", key_id), "value".to_owned()) {
+                Ok(()) => key_id += 1,
+                Err(err) => break err,
+            }
+        };
+        assert!(matches!(
+            err,
+            KvsError::StorageFull {
+                max_total_bytes: 300
+            }
+        ));
+        assert!(store.total_bytes <= 300);
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+        assert!(matches!(
+            store.bulk_set(vec![("other".to_owned(), "value".to_owned())]),
+            Err(KvsError::StorageFull { .. })
+        ));
+
+        // Removing makes room again
+        store.remove("key0".to_owned())?;
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_splits_segments() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let options = KvStoreOptions {
+            compaction_segment_bytes: Some(200),
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_storage(storage.clone(), options.clone())?;
+        set_n(&mut store, 50, "value")?;
+        store.compact()?;
+        let segments = storage.list_segments()?;
+        assert_eq!(segments[0], 2);
+        assert!(segments.len() > 1);
+        for file_num in &segments[..segments.len() - 1] {
+            assert!(storage.segment_bytes(*file_num).unwrap().len() >= 200);
+        }
+        assert_eq!(store.log.monotonic, *segments.last().unwrap());
+        assert!(store.verify()?.is_ok());
+        // The last log file keeps being appended to
+        store.set("key0".to_owned(), "new".to_owned())?;
+        assert_eq!(storage.list_segments()?, segments);
+        drop(store);
+
+        let mut store = KvStore::open_with_storage(storage.clone(), options)?;
+        assert_eq!(store.keys(None).len(), 50);
+        assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+        assert_eq!(store.get("key49".to_owned())?, Some("value".to_owned()));
+        store.compact()?;
+        assert_eq!(storage.list_segments()?[0], segments.last().unwrap() + 1);
+        Ok(())
+    }
+
+    #[test]
+    fn compact_if_ratio() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let mut store = KvStore::open_with_storage(storage.clone(), Default::default())?;
+        assert!(!store.compact_if(0.0)?);
+        set_n(&mut store, 4, "old")?;
+        set_n(&mut store, 1, "new")?;
+        // A fifth of the entries are dead
+        assert!(!store.compact_if(0.25)?);
+        assert_eq!(storage.list_segments()?, vec![1]);
+        assert!(store.compact_if(0.15)?);
+        assert_eq!(storage.list_segments()?, vec![2]);
+        assert!(!store.compact_if(0.0)?);
+        Ok(())
+    }
+
+    #[test]
+    fn set_reports_log_growth() -> KvsResult<()> {
+        let options = KvStoreOptions {
+            compaction_interval: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_storage(MemStorage::default(), options)?;
+        let first = store.set_reporting("key1".to_owned(), "value".to_owned())?;
+        assert!(first.bytes_written > 0);
+        assert_eq!(first.total_log_bytes, first.bytes_written);
+        assert!(!first.triggered_compaction);
+
+        let second = store.set_reporting("key2".to_owned(), "value".to_owned())?;
+        assert_eq!(second.total_log_bytes, 2 * first.bytes_written);
+        assert!(!second.triggered_compaction);
+
+        // Overwriting leaves a dead entry for compaction to eliminate
+        let third = store.set_reporting("key1".to_owned(), "value".to_owned())?;
+        assert!(third.triggered_compaction);
+        assert_eq!(third.total_log_bytes, 2 * first.bytes_written);
+        Ok(())
+    }
+
+    #[test]
+    fn recent_entries() -> KvsResult<()> {
+        let options = KvStoreOptions {
+            max_segment_bytes: Some(100),
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_storage(MemStorage::default(), options)?;
+        assert_eq!(store.recent_ops(5)?, vec![]);
+        set_n(&mut store, 10, "value")?;
+        store.remove("key3".to_owned())?;
+        assert!(store.log.monotonic > 1);
+
+        let recent = store.recent_ops(3)?;
+        let summaries: Vec<_> = recent
+            .iter()
+            .map(|(_, _, summary)| (summary.kind, summary.key.as_str()))
+            .collect();
+        assert_eq!(
+            summaries,
+            vec![
+                (OpKind::Set, "key8"),
+                (OpKind::Set, "key9"),
+                (OpKind::Remove, "key3")
+            ]
+        );
+        assert_eq!(store.locate("key9"), Some((recent[1].0, recent[1].1)));
+        assert_eq!(store.recent_ops(100)?.len(), 11);
+        Ok(())
+    }
+
+    #[test]
+    fn prewarm_entries() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let options = KvStoreOptions {
+            max_segment_bytes: Some(100),
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_storage(storage.clone(), options)?;
+        set_n(&mut store, 20, "value")?;
+        store.prewarm(&["key3".to_owned(), "key15".to_owned(), "missing".to_owned()])?;
+        store.prewarm_all()?;
+
+        // Entries that can't be read are reported
+        let (file_num, _) = store.locate("key0").unwrap();
+        storage.remove_segment(file_num)?;
+        assert!(store.prewarm(&["key19".to_owned()]).is_ok());
+        assert!(matches!(
+            store.prewarm(&["key0".to_owned()]),
+            Err(KvsError::Io { .. })
+        ));
+        Ok(())
+    }
 
     #[test]
-    fn parse_good_file_num() {
-        assert_eq!(Some(100_102), KvStore::parse_file_num("100102.log"));
-        assert_eq!(Some(0), KvStore::parse_file_num("0.log"));
+    fn multi_set_entries() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let mut store = KvStore::open_with_storage(storage.clone(), Default::default())?;
+        store.multi_set(vec![
+            ("key1".to_owned(), "old".to_owned()),
+            ("key2".to_owned(), "value".to_owned()),
+            ("key1".to_owned(), "new".to_owned()),
+        ])?;
+        store.multi_set(Vec::new())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+        assert_eq!(store.stats().sets, 3);
+        assert_eq!(store.recent_ops(10)?.len(), 3);
+        let (total_bytes, dead_bytes) = (store.total_bytes, store.dead_bytes);
+        drop(store);
+
+        let mut store = KvStore::open_with_storage(storage, Default::default())?;
+        assert_eq!(store.keys(None), vec!["key1", "key2"]);
+        assert_eq!(store.get("key2".to_owned())?, Some("value".to_owned()));
+        assert_eq!(
+            (store.total_bytes, store.dead_bytes),
+            (total_bytes, dead_bytes)
+        );
+        store.compact()?;
+        assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+        Ok(())
     }
 
     #[test]
-    fn parse_bad_file_num() {
-        assert_eq!(None, KvStore::parse_file_num("kvs.log"));
+    fn cut_off_batch() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let mut store = KvStore::open_with_storage(storage.clone(), Default::default())?;
+        store.set("key0".to_owned(), "value".to_owned())?;
+        // Only the first of the two entries of the batch made it to the log
+        store.log.append(&Op::Batch {
+            count: 2,
+            unix_ms: 1,
+        })?;
+        store.log.append(&Op::Set {
+            key: "key1".to_owned(),
+            value: "value".to_owned(),
+            unix_ms: 1,
+            flags: 0,
+        })?;
+        let mut follower =
+            KvStore::open_follower_with_storage(storage.clone(), Default::default())?;
+        assert_eq!(follower.keys(None), vec!["key0"]);
+
+        // Followers pick up a batch once it's complete
+        store.log.append(&Op::Set {
+            key: "key2".to_owned(),
+            value: "value".to_owned(),
+            unix_ms: 1,
+            flags: 0,
+        })?;
+        follower.refresh()?;
+        assert_eq!(follower.keys(None), vec!["key0", "key1", "key2"]);
+
+        store.log.append(&Op::Batch {
+            count: 2,
+            unix_ms: 1,
+        })?;
+        store.log.append(&Op::Set {
+            key: "key3".to_owned(),
+            value: "value".to_owned(),
+            unix_ms: 1,
+            flags: 0,
+        })?;
+        drop(store);
+        let mut store = KvStore::open_with_storage(storage.clone(), Default::default())?;
+        assert_eq!(store.keys(None), vec!["key0", "key1", "key2"]);
+        // New entries don't get mistaken for the rest of the batch
+        store.set("key4".to_owned(), "value".to_owned())?;
+        assert_eq!(storage.list_segments()?, vec![1, 2]);
+        drop(store);
+        let store = KvStore::open_with_storage(storage, Default::default())?;
+        assert_eq!(store.keys(None), vec!["key0", "key1", "key2", "key4"]);
+        Ok(())
+    }
+
+    #[test]
+    fn interned_keys_shrink_log() -> KvsResult<()> {
+        let long_key = |i: usize| format!("{}{}", "k".repeat(1000), i);
+        let options = |intern_keys| KvStoreOptions {
+            intern_keys,
+            // Never compact on its own
+            compaction_ratio: 1.0,
+            ..Default::default()
+        };
+        let mut log_lens = Vec::new();
+        for &intern_keys in &[false, true] {
+            let storage = MemStorage::default();
+            let mut store = KvStore::open_with_storage(storage.clone(), options(intern_keys))?;
+            for _ in 0..10 {
+                for i in 0..3 {
+                    store.set(long_key(i), "value".to_owned())?;
+                }
+            }
+            log_lens.push(storage.segment_bytes(1).unwrap().len());
+        }
+        // Each key is only written once instead of ten times
+        assert!(log_lens[1] * 5 < log_lens[0], "{:?}", log_lens);
+
+        let storage = MemStorage::default();
+        let mut store = KvStore::open_with_storage(storage.clone(), options(true))?;
+        store.set(long_key(0), "value0".to_owned())?;
+        store.multi_set(vec![
+            (long_key(1), "value1".to_owned()),
+            (long_key(0), "new".to_owned()),
+        ])?;
+        let mut follower = KvStore::open_follower_with_storage(storage.clone(), options(false))?;
+        assert_eq!(follower.get(long_key(0))?, Some("new".to_owned()));
+        drop(store);
+
+        // Ids defined before reopening are reused
+        let mut store = KvStore::open_with_storage(storage.clone(), options(true))?;
+        assert!(
+            store
+                .set_reporting(long_key(1), "new".to_owned())?
+                .bytes_written
+                < 100
+        );
+        assert_eq!(store.get_range(long_key(1), 1, 2)?, Some("ew".to_owned()));
+        assert!(store.verify()?.is_ok());
+        assert_eq!(store.recent_ops(1)?[0].2.key, long_key(1));
+        follower.refresh()?;
+        assert_eq!(follower.get(long_key(1))?, Some("new".to_owned()));
+
+        store.compact()?;
+        store.set(long_key(2), "value2".to_owned())?;
+        store.set(long_key(2), "new".to_owned())?;
+        drop(store);
+        let mut store = KvStore::open_with_storage(storage, Default::default())?;
+        assert_eq!(
+            store.keys(None),
+            vec![long_key(0), long_key(1), long_key(2)]
+        );
+        assert_eq!(store.get(long_key(2))?, Some("new".to_owned()));
+        assert!(store.verify()?.is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn value_flags() -> KvsResult<()> {
+        for &intern_keys in &[false, true] {
+            let storage = MemStorage::default();
+            let options = KvStoreOptions {
+                intern_keys,
+                ..Default::default()
+            };
+            let mut store = KvStore::open_with_storage(storage.clone(), options.clone())?;
+            store.set_with_flags("key1".to_owned(), "value1".to_owned(), 42)?;
+            store.set_with_flags("key2".to_owned(), "value2".to_owned(), 7)?;
+            store.set("key2".to_owned(), "value2".to_owned())?;
+            assert_eq!(
+                store.get_with_flags("key1".to_owned())?,
+                Some(("value1".to_owned(), 42))
+            );
+            assert_eq!(
+                store.get_with_flags("key2".to_owned())?,
+                Some(("value2".to_owned(), 0))
+            );
+            assert_eq!(store.get_with_flags("key3".to_owned())?, None);
+            store.rename("key1".to_owned(), "key3".to_owned())?;
+            store.rename("key3".to_owned(), "key1".to_owned())?;
+            drop(store);
+
+            let mut store = KvStore::open_with_storage(storage, options)?;
+            store.compact()?;
+            assert_eq!(
+                store.get_with_flags("key1".to_owned())?,
+                Some(("value1".to_owned(), 42))
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn follower_refreshes_interned_keys() -> KvsResult<()> {
+        let storage = MemStorage::default();
+        let options = KvStoreOptions {
+            intern_keys: true,
+            max_segment_bytes: Some(600),
+            ..Default::default()
+        };
+        let long_key = |i: usize| format!("{}{}", "k".repeat(100), i);
+        let mut store = KvStore::open_with_storage(storage.clone(), options)?;
+        for i in 0..10 {
+            store.set(long_key(i), "old".to_owned())?;
+        }
+        assert!(storage.list_segments()?.len() > 1);
+        let mut follower =
+            KvStore::open_follower_with_storage(storage.clone(), Default::default())?;
+
+        // Refers to an id defined before the follower last read the log file
+        store.set(long_key(9), "new".to_owned())?;
+        follower.refresh()?;
+        assert_eq!(follower.get(long_key(9))?, Some("new".to_owned()));
+        store.set(long_key(9), "newer".to_owned())?;
+        follower.refresh()?;
+        assert_eq!(follower.get(long_key(9))?, Some("newer".to_owned()));
+        Ok(())
     }
 }
@@ -1,8 +1,10 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, KvsResult};
+use kvs::{KvStore, KvStoreOptions, KvsError, KvsResult, LogEncoding};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
-use std::process::Command;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -17,7 +19,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -28,91 +30,796 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(eq("Key not found").trim());
 }
 
+// `kvs setnx <KEY> <VALUE>` should only set a key that isn't set yet and exit
+// with a non-zero code otherwise.
+#[test]
+fn cli_setnx() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["setnx", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["setnx", "key1", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(is_empty());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// `kvs multi-set` should set every pair given as arguments or in a file, or
+// none of them if any is invalid.
+#[test]
+fn cli_multi_set() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file = temp_dir.path().join("pairs.tsv");
+    std::fs::write(&file, "key3\tvalue3\n")?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["multi-set", "key1", "value1", "key2", "value2", "--file"])
+        .arg(&file)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    std::fs::write(&file, "key4\tvalue4\nno tab\n")?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["multi-set", "key5", "value5", "--file"])
+        .arg(&file)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("line 2"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["multi-set", "key5", "value5", "key6"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys(None), vec!["key1", "key2", "key3"]);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// `kvs get <KEY> --fail-on-missing` should print nothing to stdout and exit with
+// a non-zero code, configurable with `--null-exit-code`.
+#[test]
+fn cli_get_fail_on_missing() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--fail-on-missing"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(1)
+        .stdout(eq(""));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--null-exit-code", "3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(3)
+        .stdout(eq(""));
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "Key not found".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--fail-on-missing"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    Ok(())
+}
+
 // `kvs rm <KEY>` should print "Key not found" for an empty database and exit with non-zero code.
 #[test]
 fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(eq("Key not found").trim());
+}
+
+// `kvs import <FILE>` should set every valid line, report the invalid ones and
+// import nothing with `--strict` if any are invalid.
+#[test]
+fn cli_import() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("good.tsv"),
+        "key1\tvalue1\nkey\\t2\tline1\\nline2\\\\\n",
+    )?;
+    std::fs::write(
+        temp_dir.path().join("bad.tsv"),
+        "key3\tvalue3\nno tab\nkey4\tbad\\escape\n",
+    )?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "good.tsv"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Imported 2 keys, skipped 0 lines").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "bad.tsv", "--strict"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("line 2: missing tab"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "bad.tsv"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Imported 1 keys, skipped 2 lines").trim())
+        .stderr(contains("line 3: unknown escape sequence \\e"));
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        store.get("key\t2".to_owned())?,
+        Some("line1\nline2\\".to_owned())
+    );
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.keys(None).len(), 3);
+
+    Ok(())
+}
+
+// `kvs rmprefix <PREFIX>` should only remove keys when confirmed with `--yes`
+// and print how many were removed.
+#[test]
+fn cli_rmprefix() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("user:1".to_owned(), "value".to_owned())?;
+    store.set("user:2".to_owned(), "value".to_owned())?;
+    store.set("group:1".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rmprefix", "user:"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(is_empty())
+        .stderr(contains("--yes"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rmprefix", "user:", "--yes"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("2").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["keys"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("group:1").trim());
+
+    Ok(())
+}
+
+// `kvs rename <FROM> <TO>` should move the value and fail if `FROM` is
+// missing.
+#[test]
+fn cli_rename() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rename", "key1", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rename", "key1", "key3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(eq("Key not found").trim());
+
+    Ok(())
+}
+
+// `kvs set <KEY> <VALUE>` should print nothing and exit with zero.
+#[test]
+fn cli_set() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+}
+
+#[test]
+fn cli_get_stored() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value2").trim());
+
+    Ok(())
+}
+
+// `kvs rm <KEY>` should print nothing and exit with zero.
+#[test]
+fn cli_rm_stored() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rm", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    Ok(())
+}
+
+// `kvs compact` should print nothing, exit with zero and keep stored values.
+#[test]
+fn cli_compact() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// `kvs compact --min-ratio <RATIO>` should only compact a log that's stale
+// enough.
+#[test]
+fn cli_compact_min_ratio() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact", "--min-ratio", "0.9"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("not compacting"));
+    assert!(temp_dir.path().join("1.log").exists());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact", "--min-ratio", "0.3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    Ok(())
+}
+
+// `kvs keys [PREFIX]` should print matching keys in sorted order.
+#[test]
+fn cli_keys() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("b1".to_owned(), "value".to_owned())?;
+    store.set("a1".to_owned(), "value".to_owned())?;
+    store.set("a2".to_owned(), "value".to_owned())?;
+    store.set("b\n2".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    // Keys are escaped like `kvs scan` does, so each is on its own line
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["keys"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("a1\na2\nb\\n2\nb1\n"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["keys", "a", "--limit", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("a1\n"));
+
+    Ok(())
+}
+
+// `kvs locate <KEY>` should print the file number and offset of the entry.
+#[test]
+fn cli_locate() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let (file_num, pos) = store.locate("key2").expect("key2 was set");
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["locate", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("{} {}\n", file_num, pos));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["locate", "key3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    Ok(())
+}
+
+// `kvs scan [PREFIX]` should print every matching entry in a format `kvs
+// import` accepts.
+#[test]
+fn cli_scan() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1500 {
+        store.set(format!("key{:04}", key_id), "value".to_owned())?;
+    }
+    store.set("other".to_owned(), "line1\r\nline2\t\\".to_owned())?;
+    drop(store);
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "key"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 1500);
+    assert_eq!(lines[0], "key0000\tvalue");
+    assert_eq!(lines[1499], "key1499\tvalue");
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "other"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"other\tline1\\r\\nline2\\t\\\\\n");
+
+    // What's scanned imports back to the same entry
+    let import_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(import_dir.path().join("other.tsv"), output.stdout)?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "other.tsv"])
+        .current_dir(&import_dir)
+        .assert()
+        .success();
+    let mut store = KvStore::open(import_dir.path())?;
+    assert_eq!(
+        store.get("other".to_owned())?,
+        Some("line1\r\nline2\t\\".to_owned())
+    );
+
+    Ok(())
+}
+
+// `kvs scan` should stop quietly when whatever reads its output goes away.
+#[test]
+fn cli_scan_broken_pipe() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.bulk_set((0..10_000).map(|key_id| (format!("key{:05}", key_id), "value".repeat(10))))?;
+    drop(store);
+
+    let mut child = Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("scan")
+        .current_dir(&temp_dir)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut first_line = String::new();
+    BufReader::new(child.stdout.take().unwrap()).read_line(&mut first_line)?;
+    assert_eq!(first_line, format!("key00000\t{}\n", "value".repeat(10)));
+    let output = child.wait_with_output()?;
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+    Ok(())
+}
+
+// `kvs recent-ops` should stop quietly when whatever reads its output goes
+// away.
+#[test]
+fn cli_recent_ops_broken_pipe() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.bulk_set((0..20_000).map(|key_id| (format!("key{:05}", key_id), "value".to_owned())))?;
+    drop(store);
+
+    let mut child = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["recent-ops", "20000"])
+        .current_dir(&temp_dir)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut first_line = String::new();
+    BufReader::new(child.stdout.take().unwrap()).read_line(&mut first_line)?;
+    assert!(first_line.ends_with(" set key00000\n"), "{}", first_line);
+    let output = child.wait_with_output()?;
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+    Ok(())
+}
+
+// `kvs get-or <KEY> <DEFAULT>` should print the default for a missing key
+// without storing it, `kvs get-or-set` should store it.
+#[test]
+fn cli_get_or() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get-or", "key1", "default"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("default").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get-or-set", "key1", "first"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("first").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get-or-set", "key1", "second"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("first").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get-or", "key1", "default"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("first").trim());
+
+    Ok(())
+}
+
+// `kvs get-range <KEY> <OFFSET> <LEN>` should print part of the value.
+#[test]
+fn cli_get_range() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get-range", "key1", "2", "3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("lue").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get-range", "key1", "10", "3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get-range", "key2", "0", "3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get-range", "key1", "-1", "3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
+// `kvs checkpoint` should print where the active log file ends.
+#[test]
+fn cli_checkpoint() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let len = std::fs::metadata(temp_dir.path().join("1.log"))?.len();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("checkpoint")
         .current_dir(&temp_dir)
         .assert()
-        .failure()
-        .stdout(eq("Key not found").trim());
+        .success()
+        .stdout(format!("1 {}\n", len));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["checkpoint", "--compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("2 "));
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
 }
 
-// `kvs set <KEY> <VALUE>` should print nothing and exit with zero.
+// `kvs prewarm [KEY]...` should print nothing and exit with zero.
 #[test]
-fn cli_set() {
+fn cli_prewarm() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    for args in &[vec!["prewarm"], vec!["prewarm", "key1", "key2"]] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+    }
+
+    Ok(())
+}
+
+// `kvs recent-ops [N]` should print the last entries in the log.
+#[test]
+fn cli_recent_ops() -> KvsResult<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let (file_num, pos) = store.locate("key2").expect("key2 was set");
+    drop(store);
+
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["recent-ops", "2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
-        .stdout(is_empty());
+        .stdout(contains(format!("{} {} set key2\n", file_num, pos)))
+        .stdout(contains("rm key1\n"))
+        .stdout(contains("key1\n").count(1));
+
+    // Keys are escaped so each entry stays on its own line
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key\t3\r\n".to_owned(), "value3".to_owned())?;
+    drop(store);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["recent-ops", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(" set key\\t3\\r\\n\n"));
+
+    Ok(())
 }
 
+// `kvs last-modified <KEY>` should print when the key was last set.
 #[test]
-fn cli_get_stored() -> KvsResult<()> {
+fn cli_last_modified() -> KvsResult<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
     let mut store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
-    store.set("key2".to_owned(), "value2".to_owned())?;
+    let unix_ms = store
+        .last_modified("key1")
+        .expect("key1 was set")
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["last-modified", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
-        .stdout(eq("value1").trim());
+        .stdout(format!("{}\n", unix_ms));
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["last-modified", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
-        .stdout(eq("value2").trim());
+        .stdout(eq("Key not found").trim());
 
     Ok(())
 }
 
-// `kvs rm <KEY>` should print nothing and exit with zero.
+// `kvs disk-usage` should print the size of the log.
 #[test]
-fn cli_rm_stored() -> KvsResult<()> {
+fn cli_disk_usage() -> KvsResult<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
     let mut store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
+    let usage = store.disk_usage()?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["disk-usage"])
         .current_dir(&temp_dir)
         .assert()
         .success()
-        .stdout(is_empty());
+        .stdout(format!(
+            "log bytes: {}\nlive bytes (estimate): {}\nlog files: 1\n",
+            usage.total_log_bytes, usage.live_value_bytes_estimate
+        ));
+
+    Ok(())
+}
+
+// `kvs fsck` should succeed on an intact log.
+#[test]
+fn cli_fsck() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["fsck"])
         .current_dir(&temp_dir)
         .assert()
         .success()
-        .stdout(eq("Key not found").trim());
+        .stdout(eq("1 keys checked, 0 mismatches").trim());
 
     Ok(())
 }
@@ -121,13 +828,13 @@ fn cli_rm_stored() -> KvsResult<()> {
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -136,19 +843,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -157,13 +864,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -172,7 +879,7 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
@@ -236,6 +943,193 @@ fn get_non_existent_value() -> KvsResult<()> {
     Ok(())
 }
 
+// A successful `set` should be visible to an immediate `get`, including
+// across the automatic compaction boundary.
+#[test]
+fn read_your_writes() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..200 {
+        let value = format!("value{}", iter);
+        store.set("key1".to_owned(), value.clone())?;
+        assert_eq!(store.get("key1".to_owned())?, Some(value));
+    }
+
+    Ok(())
+}
+
+// Values written to an older log file should still be readable when a newer
+// log file is active.
+#[test]
+fn get_value_from_older_log_file() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    std::fs::File::create(temp_dir.path().join("2.log"))?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Locations should move to the new log file after compaction.
+#[test]
+fn locate_key() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.locate("key1"), None);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let (file_num1, pos1) = store.locate("key1").expect("key1 was set");
+    let (file_num2, pos2) = store.locate("key2").expect("key2 was set");
+    assert_eq!(file_num1, file_num2);
+    assert!(pos1 < pos2);
+
+    store.compact()?;
+    let (file_num, _) = store.locate("key1").expect("key1 was set");
+    assert_eq!(file_num, file_num1 + 1);
+    Ok(())
+}
+
+// The modification time of a key survives reopening and compaction.
+#[test]
+fn last_modified_key() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.last_modified("key1"), None);
+
+    let before = SystemTime::now();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let after = SystemTime::now();
+    let modified = store.last_modified("key1").expect("key1 was set");
+    // Timestamps are truncated to milliseconds
+    assert!(modified + Duration::from_millis(1) > before);
+    assert!(modified <= after);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.last_modified("key1"), Some(modified));
+    store.compact()?;
+    assert_eq!(store.last_modified("key1"), Some(modified));
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.last_modified("key1"), None);
+    Ok(())
+}
+
+// Renaming moves the value, overwriting the destination.
+#[test]
+fn rename_key() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    store.rename("key1".to_owned(), "key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    // Renaming to itself keeps the value
+    store.rename("key2".to_owned(), "key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert!(store.rename("key1".to_owned(), "key3".to_owned()).is_err());
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Setting if absent leaves existing values alone, including across reopens.
+#[test]
+fn set_if_absent() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.set_nx("key1".to_owned(), "value1".to_owned())?);
+    assert!(!store.set_nx("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    store.remove("key1".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.set_nx("key1".to_owned(), "value3".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Defaults are only stored by get_or_set.
+#[test]
+fn get_with_default() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_or("key1".to_owned(), "default".to_owned())?,
+        "default"
+    );
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(
+        store.get_or_set("key1".to_owned(), "first".to_owned())?,
+        "first"
+    );
+    assert_eq!(
+        store.get_or_set("key1".to_owned(), "second".to_owned())?,
+        "first"
+    );
+    assert_eq!(
+        store.get_or("key1".to_owned(), "default".to_owned())?,
+        "first"
+    );
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("first".to_owned()));
+    Ok(())
+}
+
+// Operation counts are saved in the data directory and pick up where they left
+// off after reopening.
+#[test]
+fn stats_persist() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let stats = store.stats();
+    assert_eq!((stats.sets, stats.gets, stats.removes), (2, 1, 1));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats(), stats);
+    for _ in 0..2000 {
+        store.get("key2".to_owned())?;
+    }
+    // Saved periodically, not only on drop
+    std::mem::forget(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.stats().gets > 1000);
+    drop(store);
+
+    // Only reading doesn't write to the data directory
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(other_dir.path())?;
+    store.get("key1".to_owned())?;
+    drop(store);
+    assert!(!other_dir.path().join("stats.json").exists());
+
+    std::fs::write(temp_dir.path().join("stats.json"), "not json")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats(), Default::default());
+    Ok(())
+}
+
 #[test]
 fn remove_non_existent_key() -> KvsResult<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -299,3 +1193,210 @@ fn compaction() -> KvsResult<()> {
 
     panic!("No compaction detected");
 }
+
+// A compaction interrupted part way through leaves a partial temporary log
+// file, which should be ignored on recovery and replaced by the next
+// compaction.
+#[test]
+fn interrupted_compaction() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let partial = temp_dir.path().join("2.log.tmp");
+    std::fs::write(
+        &partial,
+        b"{\"version\":2,\"encoding\":\"bincode\"}\n\x01\x02",
+    )?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.compact()?;
+    assert!(!partial.exists());
+    assert!(temp_dir.path().join("2.log").exists());
+    assert!(!temp_dir.path().join("1.log").exists());
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn keys_with_prefix() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("user:2".to_owned(), "value".to_owned())?;
+    store.set("user:1".to_owned(), "value".to_owned())?;
+    store.set("group:1".to_owned(), "value".to_owned())?;
+    store.remove("group:1".to_owned())?;
+
+    assert_eq!(store.keys(None), vec!["user:1", "user:2"]);
+    assert_eq!(store.keys(Some("user:2")), vec!["user:2"]);
+    assert!(store.keys(Some("group")).is_empty());
+    Ok(())
+}
+
+#[test]
+fn remove_prefix() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("user:1".to_owned(), "value".to_owned())?;
+    store.set("user:2".to_owned(), "value".to_owned())?;
+    store.set("group:1".to_owned(), "value".to_owned())?;
+
+    assert_eq!(store.remove_prefix("user:")?, 2);
+    assert_eq!(store.remove_prefix("user:")?, 0);
+    assert_eq!(store.keys(None), vec!["group:1"]);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys(None), vec!["group:1"]);
+    Ok(())
+}
+
+// A follower sees what was written before it was opened and picks up later
+// writes when refreshed.
+#[test]
+fn follower_refresh() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut follower = KvStore::open_follower(temp_dir.path())?;
+    assert_eq!(follower.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(follower.get("key2".to_owned())?, None);
+    follower.refresh()?;
+    assert_eq!(follower.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(follower
+        .set("key3".to_owned(), "value3".to_owned())
+        .is_err());
+    Ok(())
+}
+
+// Deleting the directory of an open store should be reported as such, and the
+// store should be usable again after reopening it.
+#[test]
+fn data_dir_deleted() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("db");
+    let mut store = KvStore::open(&path)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    std::fs::remove_dir_all(&path)?;
+
+    match store.set("key2".to_owned(), "value2".to_owned()) {
+        Err(KvsError::DataDirMissing { path: missing }) => assert_eq!(missing, path),
+        res => panic!("Unexpected result {:?}", res),
+    }
+    assert_eq!(store.get("key2".to_owned())?, None);
+    match store.remove("key1".to_owned()) {
+        Err(KvsError::DataDirMissing { path: missing }) => assert_eq!(missing, path),
+        res => panic!("Unexpected result {:?}", res),
+    }
+    match store.compact() {
+        Err(KvsError::DataDirMissing { path: missing }) => assert_eq!(missing, path),
+        res => panic!("Unexpected result {:?}", res),
+    }
+    store.reopen()?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(&path)?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A deleted data directory should be reported as unhealthy.
+#[test]
+fn health_after_data_dir_deleted() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("db");
+    let store = KvStore::open(&path)?;
+    assert!(store.health().ok);
+    std::fs::remove_dir_all(&path)?;
+    let health = store.health();
+    assert!(!health.ok);
+    assert!(health.detail.contains("missing"));
+    Ok(())
+}
+
+// After compaction everything in the log but the header is live.
+#[test]
+fn disk_usage() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..3 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    let before = store.disk_usage()?;
+    assert_eq!(before.num_segments, 1);
+    assert!(before.total_log_bytes > 3 * before.live_value_bytes_estimate);
+
+    store.compact()?;
+    let after = store.disk_usage()?;
+    assert_eq!(after.num_segments, 1);
+    assert_eq!(
+        after.live_value_bytes_estimate,
+        before.live_value_bytes_estimate
+    );
+    let header_len = std::fs::read(temp_dir.path().join("2.log"))?
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .unwrap() as u64
+        + 1;
+    assert_eq!(
+        after.total_log_bytes,
+        header_len + after.live_value_bytes_estimate
+    );
+    Ok(())
+}
+
+// A JSON-encoded log should be readable line by line and reopen with either
+// encoding.
+#[test]
+fn json_log_encoding() -> KvsResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let json = KvStoreOptions {
+        encoding: LogEncoding::Json,
+        ..Default::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), json.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value\n2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let log = std::fs::read_to_string(temp_dir.path().join("1.log"))?;
+    assert_eq!(log.lines().count(), 4);
+    for line in log.lines() {
+        serde_json::from_str::<serde_json::Value>(line)?;
+    }
+
+    // Switching encodings starts a new log file
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value\n2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("2.log").exists());
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), json)?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value\n2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    store.compact()?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys(None), vec!["key2", "key3"]);
+    assert_eq!(store.get("key2".to_owned())?, Some("value\n2".to_owned()));
+
+    Ok(())
+}